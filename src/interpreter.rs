@@ -0,0 +1,1299 @@
+use nannou::prelude::*;
+use std::collections::HashMap;
+use crate::parse::{self, Command, CommentlessGCodeExpr, Control, Coord, DistanceMode, Expression, Function, GCodeExpr, Operator, Param, ParseError, Plane, SpannedExpr, Unit};
+
+/// the default servo angle, from which on the pen is down
+pub const PEN_THRESHOLD: f32 = 40.0;
+
+/// the default height, up to which the pen is down
+pub const Z_THRESHOLD: f32 = 0.0;
+
+/// the assumed speed of `G0` and `G28` in mm/min
+pub const RAPID_FEED: f32 = 3000.0;
+
+/// the maximal number of nested subprogram calls, to catch runaway recursion
+const MAX_CALL_DEPTH: usize = 64;
+
+/// the maximal number of loop iterations and subprogram calls (including their repeats) of a program, to catch endless loops
+const MAX_ITERATIONS: usize = 100_000;
+
+/// the motion command, which is continued by lines without G-word
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum MotionMode { Rapid, Linear, Clockwise, Anticlockwise }
+
+/// the modal state of the plotter, which is changed by executing commands.
+/// All lengths are in millimeters, the words of the commands are converted by the active unit.
+#[derive(Copy, Clone)]
+pub struct Machine {
+    pub pos: Point2,
+    pub z: f32,
+    pub motion: MotionMode,
+    pub feed: Option<f32>, // the last `F` of a motion command
+    pub pen_down: bool,
+    pub pen_threshold: f32, // `M280` with an `S` at least this large puts the pen down
+    pub z_threshold: f32, // a move with `Z` at most this large puts the pen down
+    pub servo: u32, // the `P` of the last `M280`
+    pub pen_values: (f32, f32), // the last `S` used for pen up and pen down
+    pub laser: bool, // the pen is switched by `M3`/`M4`/`M5` instead of `M280`
+    pub power: Option<f32>, // the last `S` of `M3`/`M4` or of a move
+    pub spindle: bool, // switched on by `M3`/`M4` and off by `M5`
+    pub unit: Unit,
+    pub plane: Plane, // of the arcs
+    pub distance: DistanceMode,
+    pub arc_distance: DistanceMode,
+    pub rotation_center: Point2,
+    pub rotation: f32, // in radians
+    pub coordinate_system: usize, // 0 => G54, ..., 5 => G59
+    pub work_offsets: [Vec2; 6], // the origins of G54 to G59 in plotter coordinates, set by `G10 L2`
+    pub offset: Vec2, // of `G92`, on top of the origin of the coordinate system
+    pub z_offset: f32,
+    pub time: f32, // the estimated seconds since the start, moves without feed rate are not counted
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new(PEN_THRESHOLD, Z_THRESHOLD)
+    }
+}
+
+impl Machine {
+    /// the state at the start of a program, with the given thresholds for the pen to be down
+    pub fn new(pen_threshold: f32, z_threshold: f32) -> Self {
+        Machine {
+            pos: Vec2::ZERO,
+            z: 0.0,
+            motion: MotionMode::Rapid,
+            feed: None,
+            pen_down: false, // at first is the pen up
+            pen_threshold,
+            z_threshold,
+            servo: 0,
+            pen_values: (0.0, pen_threshold.max(50.0)),
+            laser: false,
+            power: None,
+            spindle: false,
+            unit: Unit::Millimeter,
+            plane: Plane::XY,
+            distance: DistanceMode::Absolute,
+            arc_distance: DistanceMode::Incremental,
+            rotation_center: Vec2::ZERO,
+            rotation: 0.0,
+            coordinate_system: 0,
+            work_offsets: [Vec2::ZERO; 6],
+            offset: Vec2::ZERO,
+            z_offset: 0.0,
+            time: 0.0,
+        }
+    }
+
+    /// maps a point of the (rotated) program coordinates to the plotter coordinates
+    fn rotate(&self, p: Point2) -> Point2 {
+        (p - self.rotation_center).rotate(self.rotation) + self.rotation_center
+    }
+
+    /// maps a point of the plotter coordinates to the (rotated) program coordinates
+    fn unrotate(&self, p: Point2) -> Point2 {
+        (p - self.rotation_center).rotate(-self.rotation) + self.rotation_center
+    }
+
+    /// the origin of the work coordinates, given by the coordinate system and `G92`
+    fn origin(&self) -> Vec2 {
+        self.work_offsets[self.coordinate_system] + self.offset
+    }
+
+    /// maps a point of the work coordinates to the plotter coordinates
+    pub fn plotter_point(&self, p: Point2) -> Point2 {
+        self.rotate(p + self.origin())
+    }
+
+    /// maps a point of the plotter coordinates to the work coordinates, in which the words are given
+    pub fn work_point(&self, p: Point2) -> Point2 {
+        self.unrotate(p) - self.origin()
+    }
+
+    /// the command to put the pen up or down, `M3`/`M5` for a laser and `M280` with the angles used before otherwise
+    pub fn pen_command(&self, down: bool) -> CommentlessGCodeExpr {
+        match (self.laser, down) {
+            (true, true) => CommentlessGCodeExpr::Spindle { CLKW: true, S: None },
+            (true, false) => CommentlessGCodeExpr::SpindleOff,
+            (false, _) => CommentlessGCodeExpr::Pen { P: self.servo, S: if down { self.pen_values.1 } else { self.pen_values.0 } },
+        }
+    }
+
+    /// the position together with the height
+    pub fn pos3(&self) -> Vec3 {
+        self.pos.extend(self.z)
+    }
+
+    /// converts the value of a word in the active unit to millimeters
+    pub fn length(&self, v: f32) -> f32 {
+        v * self.unit.millimeters()
+    }
+
+    /// resolves the axis words of a command to an absolute position, omitted axes keep their value
+    pub fn target(&self, x: Option<Coord>, y: Option<Coord>) -> Point2 {
+        let current = self.work_point(self.pos);
+        let resolve = |c: Option<Coord>, current: f32| match (c, self.distance) {
+            (Some(Coord::Modal(v)), DistanceMode::Absolute) => self.length(v),
+            (Some(Coord::Modal(v)), DistanceMode::Incremental) | (Some(Coord::Incremental(v)), _) => current + self.length(v),
+            (None, _) => current,
+        };
+        self.plotter_point(pt2(resolve(x, current.x), resolve(y, current.y)))
+    }
+
+    /// moves to the position given by the words and keeps the feed rate and the power, a `Z` word also sets the pen
+    fn move_to(&mut self, x: Option<Coord>, y: Option<Coord>, z: Option<Coord>, f: Option<f32>, s: Option<f32>) {
+        self.pos = self.target(x, y);
+        if let Some(z) = z {
+            self.z = match (z, self.distance) {
+                (Coord::Modal(v), DistanceMode::Absolute) => self.length(v) + self.z_offset,
+                (Coord::Modal(v), DistanceMode::Incremental) | (Coord::Incremental(v), _) => self.z + self.length(v),
+            };
+            // the threshold is a height of the work coordinates
+            self.pen_down = self.z - self.z_offset <= self.z_threshold;
+        }
+        self.feed = f.map(|f| self.length(f)).or(self.feed);
+        if let Some(s) = s {
+            self.power = Some(s);
+            if self.laser && self.spindle {
+                self.pen_down = s > 0.0;
+            }
+        }
+    }
+
+    /// whether the command draws, where the power `S` of a move already applies to the move itself.
+    /// As in GRBL, a laser is off during rapid moves
+    pub fn draws(&self, cmd: &CommentlessGCodeExpr) -> bool {
+        use CommentlessGCodeExpr::*;
+        match *cmd {
+            Move { .. } | Home { .. } if self.laser => false,
+            LinMove { S: Some(s), .. } | Arc { S: Some(s), .. } | RadiusArc { S: Some(s), .. }
+                if self.laser && self.spindle => s > 0.0,
+            _ => self.pen_down,
+        }
+    }
+
+    /// adds the time needed for a path of the given length with the feed rate in mm/min
+    fn spend(&mut self, length: f32, feed: Option<f32>) {
+        if let Some(feed) = feed.filter(|f| *f > 0.0) {
+            self.time += 60.0 * length / feed;
+        }
+    }
+
+    /// calculates the `X` and `Y` words needed to reach the absolute position `p`
+    pub fn words(&self, p: Point2) -> Vec2 {
+        let words = match self.distance {
+            DistanceMode::Absolute => self.work_point(p),
+            DistanceMode::Incremental => (p - self.pos).rotate(-self.rotation),
+        };
+        words / self.unit.millimeters()
+    }
+
+    /// resolves the `I`, `J` and `K` words of an arc to the center relative to the current position.
+    /// Omitted words are equal to the current position.
+    pub fn arc_center(&self, i: Option<f32>, j: Option<f32>, k: Option<f32>) -> Vec3 {
+        match self.arc_distance {
+            DistanceMode::Absolute => {
+                let current = self.work_point(self.pos);
+                let center = self.plotter_point(pt2(i.map_or(current.x, |i| self.length(i)), j.map_or(current.y, |j| self.length(j))));
+                (center - self.pos).extend(k.map_or(0.0, |k| self.length(k) + self.z_offset - self.z))
+            },
+            DistanceMode::Incremental => vec2(self.length(i.unwrap_or(0.0)), self.length(j.unwrap_or(0.0)))
+                .rotate(self.rotation).extend(self.length(k.unwrap_or(0.0))),
+        }
+    }
+
+    /// calculates the `I` and `J` words needed for an arc around the absolute position `center`
+    pub fn arc_words(&self, center: Point2) -> Vec2 {
+        let words = match self.arc_distance {
+            DistanceMode::Absolute => self.work_point(center),
+            DistanceMode::Incremental => (center - self.pos).rotate(-self.rotation),
+        };
+        words / self.unit.millimeters()
+    }
+
+    /// replaces a move without G-word by the command of the active motion mode
+    pub fn resolve(&self, cmd: &CommentlessGCodeExpr) -> CommentlessGCodeExpr {
+        use CommentlessGCodeExpr::*;
+        match *cmd {
+            ModalMove { X: x, Y: y, Z: z, I: i, J: j, K: k, R: r, F: f, S: s } => match (self.motion, r) {
+                (MotionMode::Rapid, _) => Move { X: x, Y: y, Z: z, F: f, S: s },
+                (MotionMode::Linear, _) => LinMove { X: x, Y: y, Z: z, F: f, S: s },
+                (MotionMode::Clockwise | MotionMode::Anticlockwise, Some(r)) =>
+                    RadiusArc { CLKW: self.motion == MotionMode::Clockwise, X: x, Y: y, Z: z, R: r, F: f, S: s },
+                (MotionMode::Clockwise | MotionMode::Anticlockwise, None) =>
+                    Arc { CLKW: self.motion == MotionMode::Clockwise, X: x, Y: y, Z: z, I: i, J: j, K: k, F: f, S: s },
+            },
+            other => other,
+        }
+    }
+
+    /// changes the state according to the command
+    pub fn execute(&mut self, cmd: &CommentlessGCodeExpr) {
+        use CommentlessGCodeExpr::*;
+        match self.resolve(cmd) {
+            Home { X: x, Y: y, Z: z } => {
+                // like Marlin, only the given axes are homed and all without axis words. The height is kept
+                let all = x.is_none() && y.is_none() && z.is_none();
+                let home = vec2(
+                    if all || x.is_some() { 0.0 } else { self.pos.x },
+                    if all || y.is_some() { 0.0 } else { self.pos.y },
+                );
+                self.spend(self.pos.distance(home), Some(RAPID_FEED));
+                self.pos = home;
+            },
+            Move { X: x, Y: y, Z: z, F: f, S: s } => {
+                self.motion = MotionMode::Rapid;
+                let (start, height) = (self.pos, self.z);
+                self.move_to(x, y, z, f, s);
+                self.spend(start.distance(self.pos).hypot(height - self.z), Some(RAPID_FEED));
+            },
+            LinMove { X: x, Y: y, Z: z, F: f, S: s } => {
+                self.motion = MotionMode::Linear;
+                let (start, height) = (self.pos, self.z);
+                self.move_to(x, y, z, f, s);
+                self.spend(start.distance(self.pos).hypot(height - self.z), self.feed);
+            },
+            Arc { CLKW: clkw, X: x, Y: y, Z: z, I: i, J: j, K: k, F: f, S: s } => {
+                self.motion = if clkw { MotionMode::Clockwise } else { MotionMode::Anticlockwise };
+                let (start, center) = (self.pos3(), self.arc_center(i, j, k));
+                self.move_to(x, y, z, f, s);
+                self.spend(arc_length(self.plane, start, self.pos3(), center, clkw), self.feed);
+            },
+            RadiusArc { CLKW: clkw, X: x, Y: y, Z: z, R: r, F: f, S: s } => {
+                self.motion = if clkw { MotionMode::Clockwise } else { MotionMode::Anticlockwise };
+                let (start, r) = (self.pos3(), self.length(r));
+                self.move_to(x, y, z, f, s);
+                let center = radius_arc_center(self.plane, start, self.pos3(), r, clkw);
+                self.spend(arc_length(self.plane, start, self.pos3(), center, clkw), self.feed);
+            },
+            ModalMove { .. } => {}, // resolved above
+            Pen { P: p, S: s } => {
+                self.laser = false;
+                self.servo = p;
+                self.pen_down = s >= self.pen_threshold;
+                if self.pen_down { self.pen_values.1 = s } else { self.pen_values.0 = s }
+            },
+            Spindle { S: s, .. } => {
+                self.laser = true;
+                self.spindle = true;
+                self.power = s.or(self.power);
+                self.pen_down = self.power.is_none_or(|s| s > 0.0);
+            },
+            SpindleOff => {
+                self.laser = true;
+                self.spindle = false;
+                self.pen_down = false;
+            },
+            Units(unit) => { self.unit = unit },
+            Plane(plane) => { self.plane = plane },
+            Dwell { P: p, S: s } => { self.time += (p.unwrap_or(0.0) / 1000.0 + s.unwrap_or(0.0)).max(0.0) },
+            Pause(_) => {}, // waits for the user
+            Distance(mode) => { self.distance = mode },
+            ArcDistance(mode) => { self.arc_distance = mode },
+            Rotate { X: x, Y: y, R: r } => {
+                self.rotation_center = pt2(self.length(x), self.length(y)) + self.origin();
+                self.rotation = r.to_radians();
+            },
+            CancelRotation => {
+                self.rotation_center = Vec2::ZERO;
+                self.rotation = 0.0;
+            },
+            SetPosition { X: x, Y: y, Z: z } => {
+                let current = self.unrotate(self.pos) - self.work_offsets[self.coordinate_system];
+                if let Some(x) = x { self.offset.x = current.x - self.length(x) }
+                if let Some(y) = y { self.offset.y = current.y - self.length(y) }
+                if let Some(z) = z { self.z_offset = self.z - self.length(z) }
+            },
+            ResetPosition => {
+                self.offset = Vec2::ZERO;
+                self.z_offset = 0.0;
+            },
+            CoordinateSystem(p) => { self.coordinate_system = p as usize - 1 },
+            SetOffset { P: p, X: x, Y: y } => {
+                let system = if p == 0 { self.coordinate_system } else { p as usize - 1 };
+                if let Some(x) = x { self.work_offsets[system].x = self.length(x) }
+                if let Some(y) = y { self.work_offsets[system].y = self.length(y) }
+            },
+            SubProgram(_) | Call { .. } | Return | ProgramEnd(_) => {}, // handled by `expand`
+        }
+    }
+}
+
+/// the coordinates of a point (or vector) in the plane in the order of `Plane`, followed by the coordinate along the third axis
+pub fn to_plane(plane: Plane, p: Vec3) -> Vec3 {
+    match plane {
+        Plane::XY => p,
+        Plane::ZX => vec3(p.z, p.x, p.y),
+        Plane::YZ => vec3(p.y, p.z, p.x),
+    }
+}
+
+/// the inverse of `to_plane`
+pub fn from_plane(plane: Plane, p: Vec3) -> Vec3 {
+    match plane {
+        Plane::XY => p,
+        Plane::ZX => vec3(p.y, p.z, p.x),
+        Plane::YZ => vec3(p.z, p.x, p.y),
+    }
+}
+
+/// the plane, onto which the plot is projected
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum View { XY, XZ, YZ }
+
+impl View {
+    /// the coordinates of a point in the view
+    pub fn project(self, p: Vec3) -> Vec2 {
+        match self {
+            View::XY => p.truncate(),
+            View::XZ => vec2(p.x, p.z),
+            View::YZ => vec2(p.y, p.z),
+        }
+    }
+
+    /// whether arcs in the plane are drawn mirrored, `None` if the view shows another plane
+    pub fn mirrors(self, plane: Plane) -> Option<bool> {
+        match (self, plane) {
+            (View::XY, Plane::XY) | (View::YZ, Plane::YZ) => Some(false),
+            (View::XZ, Plane::ZX) => Some(true), // seen from the negative Y axis
+            _ => None,
+        }
+    }
+
+    /// the names of the horizontal and vertical axes
+    pub fn axes(self) -> (char, char) {
+        match self {
+            View::XY => ('X', 'Y'),
+            View::XZ => ('X', 'Z'),
+            View::YZ => ('Y', 'Z'),
+        }
+    }
+
+    /// the next view, or the previous one if `back`
+    pub fn next(self, back: bool) -> Self {
+        match (self, back) {
+            (View::XY, false) | (View::YZ, true) => View::XZ,
+            (View::XZ, false) | (View::XY, true) => View::YZ,
+            (View::YZ, false) | (View::XZ, true) => View::XY,
+        }
+    }
+}
+
+impl std::str::FromStr for View {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xy" => Ok(View::XY),
+            "xz" => Ok(View::XZ),
+            "yz" => Ok(View::YZ),
+            _ => Err(format!("`{s}` is no view, use `xy`, `xz` or `yz`")),
+        }
+    }
+}
+
+/// calculates the arc center relative to `start` (like `I`/`J`/`K`) for the `R` form of `G2`/`G3` in the plane.
+/// A positive radius selects the shorter arc and a negative radius the one larger than a half circle.
+pub fn radius_arc_center(plane: Plane, start: Vec3, end: Vec3, r: f32, clkw: bool) -> Vec3 {
+    let chord = to_plane(plane, end - start).truncate();
+    let half_chord = chord.length() / 2.0;
+    let height = (r * r - half_chord * half_chord).max(0.0).sqrt();
+    let left = chord.perp().normalize_or_zero(); // coinciding points give no arc
+    // the short clockwise arc turns around a center right of the chord
+    let normal = if clkw == (r > 0.0) { -left } else { left };
+    from_plane(plane, (chord / 2.0 + normal * height).extend(0.0))
+}
+
+/// the angle of the arc from `start` to `end` around `start + center` in the plane, which is a full circle if both coincide
+fn arc_angle(plane: Plane, start: Vec3, end: Vec3, center: Vec3, clkw: bool) -> f32 {
+    let a = -to_plane(plane, center).truncate();
+    let b = to_plane(plane, end - start - center).truncate();
+    let angle = a.perp_dot(b).atan2(a.dot(b)); // anticlockwise from `a` to `b`
+    let angle = if clkw { -angle } else { angle };
+    if angle <= 0.0 { angle + 2.0 * PI } else { angle }
+}
+
+/// the length of the arc from `start` to `end` around `start + center` in the plane, without the move along the third axis
+pub fn arc_length(plane: Plane, start: Vec3, end: Vec3, center: Vec3, clkw: bool) -> f32 {
+    to_plane(plane, center).truncate().length() * arc_angle(plane, start, end, center, clkw)
+}
+
+/// `steps + 1` points along the arc from `start` to `end` around `start + center` in the plane,
+/// where the third axis changes evenly (a helix)
+pub fn arc_points(plane: Plane, start: Vec3, end: Vec3, center: Vec3, clkw: bool, steps: usize) -> Vec<Vec3> {
+    let angle = arc_angle(plane, start, end, center, clkw) * if clkw { -1.0 } else { 1.0 };
+    let a = -to_plane(plane, center).truncate();
+    let (start, end) = (to_plane(plane, start), to_plane(plane, end));
+    let middle = start.truncate() - a;
+    (0..=steps).map(|n| {
+        let t = n as f32 / steps as f32;
+        from_plane(plane, (middle + a.rotate(angle * t)).extend(start.z + (end.z - start.z) * t))
+    }).collect()
+}
+
+/// the values of the parameters set by `#1 = ...`
+pub type Parameters = HashMap<Param, f32>;
+
+/// evaluates an expression and fails with the first parameter, which is not set
+pub fn evaluate(expr: &Expression, params: &Parameters) -> Result<f32, Param> {
+    Ok(match expr {
+        Expression::Number(v) => *v,
+        Expression::Param(param) => *params.get(param).ok_or_else(|| param.clone())?,
+        Expression::Negate(e) => -evaluate(e, params)?,
+        Expression::Binary(operator, a, b) => {
+            let (a, b) = (evaluate(a, params)?, evaluate(b, params)?);
+            match operator {
+                Operator::Add => a + b,
+                Operator::Subtract => a - b,
+                Operator::Multiply => a * b,
+                Operator::Divide => a / b,
+                Operator::Modulo => a.rem_euclid(b),
+                Operator::Power => a.powf(b),
+                Operator::Equal => truth(a == b),
+                Operator::NotEqual => truth(a != b),
+                Operator::Greater => truth(a > b),
+                Operator::GreaterEqual => truth(a >= b),
+                Operator::Less => truth(a < b),
+                Operator::LessEqual => truth(a <= b),
+                Operator::And => truth(a != 0.0 && b != 0.0),
+                Operator::Or => truth(a != 0.0 || b != 0.0),
+                Operator::Xor => truth((a != 0.0) != (b != 0.0)),
+            }
+        },
+        Expression::Function(function, e) => {
+            let v = evaluate(e, params)?;
+            match function {
+                Function::Abs => v.abs(),
+                Function::Acos => v.acos().to_degrees(),
+                Function::Asin => v.asin().to_degrees(),
+                Function::Cos => v.to_radians().cos(),
+                Function::Exp => v.exp(),
+                Function::Fix => v.floor(),
+                Function::Fup => v.ceil(),
+                Function::Round => v.round(),
+                Function::Ln => v.ln(),
+                Function::Sin => v.to_radians().sin(),
+                Function::Sqrt => v.sqrt(),
+                Function::Tan => v.to_radians().tan(),
+            }
+        },
+        Expression::Atan(y, x) => evaluate(y, params)?.atan2(evaluate(x, params)?).to_degrees(),
+    })
+}
+
+/// the value of a condition
+fn truth(b: bool) -> f32 {
+    if b { 1.0 } else { 0.0 }
+}
+
+/// problems while running the program flow
+pub enum ProgramError {
+    MissingSubProgram { line: usize, program: u32 },
+    RecursionLimit { line: usize },
+    UndefinedParameter { line: usize, param: Param },
+    InvalidValue { line: usize }, // like a division by zero
+    InvalidBlock(ParseError), // the commands with the values filled in
+    UnmatchedControl { line: usize, text: String },
+    IterationLimit { line: usize },
+    MissingReturn { line: usize, program: u32 }, // the subprogram was skipped
+}
+
+impl std::fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProgramError::MissingSubProgram { line, program } =>
+                write!(f, "Cannot call subprogram in line {}, there is no `O{program}`.", line + 1),
+            ProgramError::RecursionLimit { line } =>
+                write!(f, "Cannot call subprogram in line {}, more than {MAX_CALL_DEPTH} calls are nested.", line + 1),
+            ProgramError::UndefinedParameter { line, param } =>
+                write!(f, "Cannot run line {}, the parameter `{param}` is not set.", line + 1),
+            ProgramError::InvalidValue { line } =>
+                write!(f, "Cannot run line {}, an expression is not a finite number.", line + 1),
+            ProgramError::InvalidBlock(error) => write!(f, "{error}"),
+            ProgramError::UnmatchedControl { line, text } =>
+                write!(f, "Cannot run line {}, `{text}` is not part of a complete block.", line + 1),
+            ProgramError::IterationLimit { line } =>
+                write!(f, "Cannot run line {}, the loops and subprogram calls run more than {MAX_ITERATIONS} times.", line + 1),
+            ProgramError::MissingReturn { line, program } =>
+                write!(f, "Skipped the subprogram `O{program}` in line {}, it is not called and has no `M99` before the next `O`.", line + 1),
+        }
+    }
+}
+
+/// a running subprogram call
+struct CallFrame {
+    start: usize,
+    return_to: usize,
+    repeats_left: u32,
+}
+
+/// the lines of a control block, given by their positions in the program
+#[derive(Copy, Clone)]
+struct Block {
+    start: usize, // the `if`, `while` or `repeat`
+    next: usize, // the next branch of an `if`, otherwise the end
+    end: usize, // the `endif`, `endwhile` or `endrepeat`
+}
+
+/// a block, whose end is not found yet
+struct OpenBlock {
+    number: u32,
+    start: usize,
+    is_loop: bool,
+    has_else: bool,
+    branches: Vec<usize>, // `elseif` and `else`
+    exits: Vec<usize>, // `break` and `continue`
+}
+
+/// finds the blocks of all control lines, where lines without a complete block are missing
+fn match_blocks(program: &[&SpannedExpr]) -> HashMap<usize, Block> {
+    let mut blocks = HashMap::new();
+    let mut open: Vec<OpenBlock> = Vec::new();
+    for (i, e) in program.iter().enumerate() {
+        let GCodeExpr::Control(number, control, _) = &e.expr else { continue };
+        let top = open.last_mut().filter(|b| b.number == *number);
+        match control {
+            Control::If(_) | Control::While(_) | Control::Repeat(_) => open.push(OpenBlock {
+                number: *number, start: i, is_loop: !matches!(control, Control::If(_)), has_else: false, branches: Vec::new(), exits: Vec::new()
+            }),
+            Control::ElseIf(_) | Control::Else => if let Some(b) = top.filter(|b| !b.is_loop && !b.has_else) {
+                b.branches.push(i);
+                b.has_else = matches!(control, Control::Else);
+            },
+            Control::Break | Control::Continue => if let Some(b) = open.iter_mut().rev().find(|b| b.number == *number && b.is_loop) {
+                b.exits.push(i);
+            },
+            Control::EndIf | Control::EndWhile | Control::EndRepeat => {
+                let closes = |b: &OpenBlock| match control {
+                    Control::EndIf => !b.is_loop,
+                    Control::EndWhile => matches!(program[b.start].expr, GCodeExpr::Control(_, Control::While(_), _)),
+                    _ => matches!(program[b.start].expr, GCodeExpr::Control(_, Control::Repeat(_), _)),
+                };
+                if let Some(b) = top.filter(|b| closes(b)) {
+                    let chain: Vec<_> = std::iter::once(b.start).chain(b.branches.iter().copied()).collect();
+                    for (k, &branch) in chain.iter().enumerate() {
+                        blocks.insert(branch, Block { start: b.start, next: chain.get(k + 1).copied().unwrap_or(i), end: i });
+                    }
+                    for &exit in b.exits.iter().chain([i].iter()) {
+                        blocks.insert(exit, Block { start: b.start, next: i, end: i });
+                    }
+                    open.pop();
+                }
+            },
+        }
+    }
+    blocks
+}
+
+/// evaluates all expressions of a line, such that they are not affected by the other assignments of the line
+fn evaluate_all<'e>(line: usize, exprs: impl Iterator<Item = &'e Expression>, params: &Parameters) -> Result<Vec<f32>, ProgramError> {
+    exprs.map(|expr| match evaluate(expr, params) {
+        Ok(v) if v.is_finite() => Ok(v),
+        Ok(_) => Err(ProgramError::InvalidValue { line }),
+        Err(param) => Err(ProgramError::UndefinedParameter { line, param }),
+    }).collect()
+}
+
+/// the positions of the items of a line in the order, in which they are run. The commands are ordered by their modal groups,
+/// while the other items (`None`) keep their place
+fn run_order(groups: &[Option<u8>]) -> Vec<usize> {
+    let slots: Vec<_> = (0..groups.len()).filter(|&k| groups[k].is_some()).collect();
+    let mut sorted = slots.clone();
+    sorted.sort_by_key(|&k| groups[k]);
+    let mut order: Vec<_> = (0..groups.len()).collect();
+    for (slot, k) in slots.into_iter().zip(sorted) {
+        order[slot] = k;
+    }
+    order
+}
+
+/// counts a loop iteration or a subprogram call, which fails above `MAX_ITERATIONS`
+fn count_iteration(iterations: &mut usize, line: usize) -> Result<(), ProgramError> {
+    *iterations += 1;
+    if *iterations > MAX_ITERATIONS {
+        return Err(ProgramError::IterationLimit { line });
+    }
+    Ok(())
+}
+
+/// executes the parameters, the control flow and the subprogram calls and returns the commands in the order in which they are run,
+/// including the unknown commands, grouped by the passes over the lines. The main program ends with `M2`/`M30`, with `M99` or at the end of the file.
+/// Lines, which cannot be evaluated, are skipped and returned as errors, while errors of the control flow and the calls stop the program.
+pub fn expand(exprs: &[SpannedExpr]) -> (Vec<(usize, Vec<Command>)>, Vec<ProgramError>) {
+    use CommentlessGCodeExpr::*;
+    let program: Vec<_> = exprs.iter()
+        .filter(|e| matches!(e.expr, GCodeExpr::Code(_) | GCodeExpr::Unknown(_) | GCodeExpr::Parametric(_) | GCodeExpr::Assign(..) | GCodeExpr::Control(..)))
+        .collect();
+    let blocks = match_blocks(&program);
+    let control_at = |k: usize| match &program[k].expr {
+        GCodeExpr::Control(_, control, _) => control,
+        _ => unreachable!(),
+    };
+    let mut programs = HashMap::new();
+    // a subprogram, which is reached without call, is skipped up to its `M99` (true) or up to the next `O` (false).
+    // An `O` without `M99` before `M2`/`M30` or the end of the file names the main program and is run,
+    // as does the first `O` before any call and `M99`, which is followed by the subprograms
+    let mut bodies = HashMap::new();
+    let mut first = true;
+    for (i, e) in program.iter().enumerate() {
+        match e.expr {
+            GCodeExpr::Code(Call { .. } | Return) => first = false,
+            GCodeExpr::Code(SubProgram(p)) => {
+                programs.entry(p).or_insert(i + 1);
+                let end = program[i + 1..].iter().position(|e| matches!(e.expr, GCodeExpr::Code(Return | ProgramEnd(_) | SubProgram(_))));
+                match end.map(|k| (i + 1 + k, &program[i + 1 + k].expr)) {
+                    Some((k, GCodeExpr::Code(Return))) => bodies.insert(i, (k + 1, true)),
+                    Some((k, GCodeExpr::Code(SubProgram(_)))) if !first => bodies.insert(i, (k, false)),
+                    _ => None,
+                };
+                first = false;
+            },
+            _ => {},
+        }
+    }
+
+    let mut expanded = Vec::with_capacity(program.len());
+    // control lines without a complete block are reported, whether they are reached or not
+    let mut errors: Vec<_> = program.iter().enumerate().filter_map(|(k, e)| match &e.expr {
+        GCodeExpr::Control(_, _, text) if !blocks.contains_key(&k) => Some(ProgramError::UnmatchedControl { line: e.line, text: text.to_string() }),
+        _ => None,
+    }).collect();
+    let mut params = Parameters::new();
+    let mut repeats: HashMap<usize, u32> = HashMap::new(); // the iterations left of the running `repeat` blocks
+    let mut iterations = 0;
+    let mut stack: Vec<CallFrame> = Vec::new();
+    let mut i = 0;
+    'run: while let Some(e) = program.get(i) {
+        let l = e.line;
+        i += 1;
+        let cmds = match &e.expr {
+            GCodeExpr::Code(_) | GCodeExpr::Unknown(_) | GCodeExpr::Parametric(_) => {
+                // the commands of the (rest of the) line, each with the position of its item
+                let mut line = Vec::new();
+                let mut k = i - 1;
+                while let Some(item) = program.get(k).filter(|item| item.line == l) {
+                    match &item.expr {
+                        GCodeExpr::Code(cmd) => line.push((k, Command::Code(*cmd))),
+                        GCodeExpr::Unknown(block) => line.push((k, Command::Unknown(block.to_string()))),
+                        GCodeExpr::Parametric(block) => {
+                            let cmds = evaluate_all(l, block.values.iter().map(|(_, expr)| expr), &params)
+                                .and_then(|values| parse::parse_block(block, l, &values).map_err(ProgramError::InvalidBlock));
+                            match cmds {
+                                Ok(cmds) => line.extend(cmds.into_iter().map(|cmd| (k, cmd))),
+                                Err(error) => errors.push(error),
+                            }
+                        },
+                        _ => break,
+                    }
+                    k += 1;
+                }
+                i = k;
+                let groups: Vec<_> = line.iter().map(|(_, cmd)| match cmd {
+                    Command::Code(cmd) => Some(cmd.modal_group().1),
+                    Command::Unknown(_) => None,
+                }).collect();
+                let mut line: Vec<_> = line.into_iter().map(Some).collect();
+                run_order(&groups).into_iter().filter_map(|k| line[k].take()).collect::<Vec<_>>()
+            },
+            GCodeExpr::Assign(..) => {
+                // all assignments of the line
+                let mut assignments = vec![e];
+                while let Some(next) = program.get(i).filter(|next| next.line == l && matches!(next.expr, GCodeExpr::Assign(..))) {
+                    assignments.push(next);
+                    i += 1;
+                }
+                let exprs = assignments.iter().filter_map(|a| match &a.expr {
+                    GCodeExpr::Assign(_, expr, _) => Some(expr),
+                    _ => None,
+                });
+                match evaluate_all(l, exprs, &params) {
+                    Ok(values) => for (a, v) in assignments.iter().zip(values) {
+                        if let GCodeExpr::Assign(param, _, _) = &a.expr {
+                            params.insert(param.clone(), v);
+                        }
+                    },
+                    Err(error) => errors.push(error),
+                }
+                continue;
+            },
+            GCodeExpr::Control(_, control, _) => {
+                // reported above
+                let Some(&block) = blocks.get(&(i - 1)) else { break 'run };
+                let condition = |expr| evaluate_all(l, std::iter::once(expr), &params).map(|values| values[0]);
+                // whether the loop body is run once more
+                let mut iterate = false;
+                match control {
+                    // the first branch, whose condition is true, is run
+                    Control::If(_) => {
+                        let mut k = i - 1;
+                        while let Control::If(expr) | Control::ElseIf(expr) = control_at(k) {
+                            match condition(expr) {
+                                Ok(v) if v != 0.0 => break,
+                                Ok(_) => k = blocks[&k].next,
+                                Err(error) => {
+                                    errors.push(error);
+                                    break 'run;
+                                }
+                            }
+                        }
+                        i = k + 1;
+                    },
+                    // reached at the end of a branch, which was run
+                    Control::ElseIf(_) | Control::Else => i = block.end + 1,
+                    Control::EndIf => {},
+                    Control::While(expr) => match condition(expr) {
+                        Ok(v) if v != 0.0 => iterate = true,
+                        Ok(_) => i = block.end + 1,
+                        Err(error) => {
+                            errors.push(error);
+                            break 'run;
+                        }
+                    },
+                    Control::EndWhile => i = block.start,
+                    Control::Repeat(expr) => match condition(expr) {
+                        Ok(n) if n >= 1.0 => {
+                            repeats.insert(block.start, n as u32 - 1);
+                            iterate = true;
+                        },
+                        Ok(_) => i = block.end + 1,
+                        Err(error) => {
+                            errors.push(error);
+                            break 'run;
+                        }
+                    },
+                    Control::EndRepeat => match repeats.get_mut(&block.start) {
+                        Some(left) if *left > 0 => {
+                            *left -= 1;
+                            i = block.start + 1;
+                            iterate = true;
+                        },
+                        _ => {
+                            repeats.remove(&block.start);
+                        },
+                    },
+                    Control::Break => {
+                        repeats.remove(&block.start);
+                        i = block.end + 1;
+                    },
+                    // the end starts the next iteration
+                    Control::Continue => i = block.end,
+                }
+                if iterate {
+                    if let Err(error) = count_iteration(&mut iterations, l) {
+                        errors.push(error);
+                        break 'run;
+                    }
+                }
+                continue;
+            },
+            _ => unreachable!(),
+        };
+        // the commands of this pass over the line
+        expanded.push((l, Vec::new()));
+        let pass = expanded.len() - 1;
+        for (k, cmd) in cmds {
+            let Command::Code(code) = cmd else {
+                expanded[pass].1.push(cmd);
+                continue;
+            };
+            match code {
+                SubProgram(p) => if let Some(&(end, returns)) = bodies.get(&k) {
+                    if !returns {
+                        errors.push(ProgramError::MissingReturn { line: l, program: p });
+                    }
+                    i = end;
+                    break;
+                },
+                Call { P: p, L: l_count } => {
+                    if l_count > 0 {
+                        let Some(&start) = programs.get(&p) else {
+                            errors.push(ProgramError::MissingSubProgram { line: l, program: p });
+                            break 'run;
+                        };
+                        if stack.len() >= MAX_CALL_DEPTH {
+                            errors.push(ProgramError::RecursionLimit { line: l });
+                            break 'run;
+                        }
+                        if let Err(error) = count_iteration(&mut iterations, l) {
+                            errors.push(error);
+                            break 'run;
+                        }
+                        stack.push(CallFrame { start, return_to: i, repeats_left: l_count - 1 });
+                        i = start;
+                    }
+                    // the call is the last command of its line
+                    break;
+                },
+                Return => {
+                    match stack.last_mut() {
+                        Some(frame) if frame.repeats_left > 0 => {
+                            if let Err(error) = count_iteration(&mut iterations, l) {
+                                errors.push(error);
+                                break 'run;
+                            }
+                            frame.repeats_left -= 1;
+                            i = frame.start;
+                        },
+                        Some(frame) => {
+                            i = frame.return_to;
+                            stack.pop();
+                        },
+                        None => break 'run,
+                    }
+                    break;
+                },
+                ProgramEnd(_) => break 'run,
+                _ => expanded[pass].1.push(cmd),
+            }
+        }
+    }
+    // like the lines with only calls
+    expanded.retain(|(_, pass)| !pass.is_empty());
+    (expanded, errors)
+}
+
+/// how `transform` changes the lengths of a file, where the scaling happens before the shift and both in the new unit
+#[derive(Copy, Clone)]
+pub struct Transformation {
+    pub shift: Vec2,
+    pub scale: f32,
+    pub units: Option<Unit>, // all lengths are converted to this unit
+}
+
+/// transforms the commands of a file and returns them together with warnings about the parts, which are not transformed.
+/// A file, whose lengths are converted, starts with the new unit, unless it sets the unit before the first length
+pub fn transform<'a>(commands: Vec<(usize, GCodeExpr<'a>)>, transformation: Transformation) -> (Vec<(usize, GCodeExpr<'a>)>, Vec<String>) {
+    use CommentlessGCodeExpr::*;
+    let mut transformed = Vec::with_capacity(commands.len() + 1);
+    let mut warnings = Vec::new();
+    let mut machine = Machine::default();
+    // whether a `G20`/`G21` comes before the first command with lengths
+    let mut units_first = None;
+    let mut warned_plane = false;
+    let mut warned_parametric = false;
+    // which axes of the current position are shifted like the words, neither is at the start and after homing
+    let mut shifted = (false, false);
+    let mut commands = commands.into_iter().peekable();
+    while let Some((l, _)) = commands.peek() {
+        let l = *l;
+        let mut line = Vec::new();
+        while let Some((_, cmd)) = commands.next_if(|(next, _)| *next == l) {
+            line.push(cmd);
+        }
+        // the commands are run in the order of their modal groups and keep their place in the line,
+        // each is transformed in the state after running it
+        let groups: Vec<_> = line.iter().map(|cmd| match cmd {
+            GCodeExpr::Code(c) => Some(c.modal_group().1),
+            _ => None,
+        }).collect();
+        let mut states = vec![(machine, shifted); line.len()];
+        for k in run_order(&groups) {
+            if let GCodeExpr::Code(c) = &line[k] {
+                machine.execute(c);
+                shifted = shifted_axes(&machine.resolve(c), &machine, shifted);
+            }
+            if units_first.is_none() {
+                units_first = match line[k] {
+                    GCodeExpr::Code(Units(_)) => Some(true),
+                    GCodeExpr::Code(Move { .. } | LinMove { .. } | Arc { .. } | RadiusArc { .. } | ModalMove { .. } | Rotate { .. } | SetPosition { .. } | SetOffset { .. })
+                        | GCodeExpr::Parametric(_) => Some(false),
+                    _ => None,
+                };
+            }
+            states[k] = (machine, shifted);
+        }
+        for (cmd, (state, shifted)) in line.into_iter().zip(states) {
+            // `Z` and `K` are not scaled, which distorts arcs in the XZ and YZ planes
+            if transformation.scale != 1.0 && state.plane != parse::Plane::XY && !warned_plane && matches!(cmd, GCodeExpr::Code(Arc { .. } | RadiusArc { .. } | ModalMove { .. })) {
+                warnings.push(format!("line {}: arcs in `G18`/`G19` are distorted by scaling, since `Z` is kept as it is", l + 1));
+                warned_plane = true;
+            }
+            // the values are only known while running
+            if !warned_parametric && matches!(cmd, GCodeExpr::Parametric(_) | GCodeExpr::Assign(..)) {
+                warnings.push(format!("line {}: parameters and expressions are kept as they are and not transformed", l + 1));
+                warned_parametric = true;
+            }
+            transformed.push((l, match cmd {
+                GCodeExpr::Code(c) => GCodeExpr::Code(transform_command(c, &state, shifted, transformation)),
+                other => other,
+            }));
+        }
+    }
+    if let (Some(unit), None | Some(false)) = (transformation.units, units_first) {
+        transformed = std::iter::once((0, GCodeExpr::Code(Units(unit))))
+            .chain(transformed.into_iter().map(|(l, cmd)| (l + 1, cmd)))
+            .collect();
+    }
+    (transformed, warnings)
+}
+
+/// which axes of the position are shifted by `transform` after running the command, where `shifted` is the state before.
+/// Homing leaves the position unshifted, absolute moves shift it and incremental moves keep it as it is
+fn shifted_axes(cmd: &CommentlessGCodeExpr, machine: &Machine, shifted: (bool, bool)) -> (bool, bool) {
+    use CommentlessGCodeExpr::*;
+    let modal = |c: Option<Coord>| matches!(c, Some(Coord::Modal(_)));
+    match *cmd {
+        Home { X: x, Y: y, Z: z } if x.is_some() || y.is_some() || z.is_some() => (shifted.0 && x.is_none(), shifted.1 && y.is_none()),
+        Home { .. } => (false, false),
+        Move { X: x, Y: y, .. } | LinMove { X: x, Y: y, .. } | Arc { X: x, Y: y, .. } | RadiusArc { X: x, Y: y, .. }
+            if machine.distance == DistanceMode::Absolute && (modal(x) || modal(y)) =>
+            // a rotated axis word moves both axes
+            if machine.rotation != 0.0 { (true, true) } else { (shifted.0 || modal(x), shifted.1 || modal(y)) },
+        _ => shifted,
+    }
+}
+
+/// transforms the words of a command, where `machine` is the state after running it and `shifted` tells, which axes of
+/// its position are shifted already. Incremental words are only scaled, `Z`, `K` and `F` are only converted to the new unit
+fn transform_command(cmd: CommentlessGCodeExpr, machine: &Machine, shifted: (bool, bool), transformation: Transformation) -> CommentlessGCodeExpr {
+    use CommentlessGCodeExpr::*;
+    let (dx, dy) = (transformation.shift.x, transformation.shift.y);
+    // the conversion from the active unit
+    let k = transformation.units.map_or(1.0, |unit| machine.unit.millimeters() / unit.millimeters());
+    let ds = transformation.scale * k;
+    let (mx, my) = if machine.distance == DistanceMode::Absolute { (dx, dy) } else { (0.0, 0.0) };
+    let (ix, iy) = if machine.arc_distance == DistanceMode::Absolute { (dx, dy) } else { (0.0, 0.0) };
+    let x = |x: Option<Coord>| x.map(|x| x.transform(ds, mx));
+    let y = |y: Option<Coord>| y.map(|y| y.transform(ds, my));
+    let z = |z: Option<Coord>| z.map(|z| z.transform(k, 0.0));
+    let f = |f: Option<f32>| f.map(|f| f * k);
+    match cmd {
+        Move { X: x0, Y: y0, Z: z0, F: f0, S: s } => Move { X: x(x0), Y: y(y0), Z: z(z0), F: f(f0), S: s },
+        LinMove { X: x0, Y: y0, Z: z0, F: f0, S: s } => LinMove { X: x(x0), Y: y(y0), Z: z(z0), F: f(f0), S: s },
+        Arc { CLKW: clkw, X: x0, Y: y0, Z: z0, I: i, J: j, K: k0, F: f0, S: s } => Arc {
+            CLKW: clkw, X: x(x0), Y: y(y0), Z: z(z0), I: i.map(|i| i * ds + ix), J: j.map(|j| j * ds + iy), K: f(k0), F: f(f0), S: s
+        },
+        RadiusArc { CLKW: clkw, X: x0, Y: y0, Z: z0, R: r, F: f0, S: s } => RadiusArc {
+            CLKW: clkw, X: x(x0), Y: y(y0), Z: z(z0), R: r * ds, F: f(f0), S: s
+        },
+        ModalMove { X: x0, Y: y0, Z: z0, I: i, J: j, K: k0, R: r, F: f0, S: s } => ModalMove {
+            X: x(x0), Y: y(y0), Z: z(z0), I: i.map(|i| i * ds + ix), J: j.map(|j| j * ds + iy), K: f(k0), R: r.map(|r| r * ds), F: f(f0), S: s
+        },
+        Rotate { X: x0, Y: y0, R: r } => Rotate { X: x0 * ds + dx, Y: y0 * ds + dy, R: r },
+        // the position keeps its coordinates, thus they are only shifted, if the position is shifted
+        SetPosition { X: x0, Y: y0, Z: z0 } => SetPosition {
+            X: x0.map(|x| x * ds + if shifted.0 { dx } else { 0.0 }), Y: y0.map(|y| y * ds + if shifted.1 { dy } else { 0.0 }), Z: f(z0)
+        },
+        // the words are shifted already, thus the origins are only scaled
+        SetOffset { P: p, X: x0, Y: y0 } => SetOffset { P: p, X: x0.map(|x| x * ds), Y: y0.map(|y| y * ds) },
+        Units(unit) => Units(transformation.units.unwrap_or(unit)),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// evaluates an expression without parameters
+    fn eval(expr: &str) -> f32 {
+        let file = format!("#1 = {expr}");
+        let (exprs, errors) = parse::parse_gcode(&file);
+        assert!(errors.is_empty(), "cannot parse `{expr}`");
+        match &exprs[0].expr {
+            GCodeExpr::Assign(_, value, _) => evaluate(value, &Parameters::new()).unwrap_or(f32::NAN),
+            _ => panic!("`{expr}` is not an assignment"),
+        }
+    }
+
+    /// the expanded commands, one pass over a line each, and the error messages of a program
+    fn run(file: &str) -> (Vec<String>, Vec<String>) {
+        let (exprs, errors) = parse::parse_gcode(file);
+        assert!(errors.is_empty(), "cannot parse the program");
+        let (commands, errors) = expand(&exprs);
+        let commands = commands.iter().map(|(_, pass)| pass.iter().map(|cmd| match cmd {
+            Command::Code(cmd) => cmd.as_str(),
+            Command::Unknown(block) => block.clone(),
+        }).collect::<Vec<_>>().join(" "));
+        (commands.collect(), errors.iter().map(|e| e.to_string()).collect())
+    }
+
+    /// the machine after running a program
+    fn machine(file: &str) -> Machine {
+        let (exprs, errors) = parse::parse_gcode(file);
+        assert!(errors.is_empty(), "cannot parse the program");
+        let mut machine = Machine::default();
+        for cmd in expand(&exprs).0.into_iter().flat_map(|(_, pass)| pass) {
+            if let Command::Code(cmd) = cmd {
+                machine.execute(&cmd);
+            }
+        }
+        machine
+    }
+
+    /// the commands of a file after the transformation, each in its own line
+    fn transformed(file: &str, shift: Vec2, scale: f32, units: Option<Unit>) -> String {
+        let (exprs, errors) = parse::parse_gcode(file);
+        assert!(errors.is_empty(), "cannot parse the program");
+        let commands = exprs.into_iter().map(|e| (e.line, e.expr)).collect();
+        let (commands, _) = transform(commands, Transformation { shift, scale, units });
+        commands.iter().map(|(_, cmd)| cmd.as_str()).collect::<Vec<_>>().join("\n")
+    }
+
+    #[test]
+    fn transform_incremental() {
+        // incremental words are only scaled
+        assert_eq!(transformed("G91\nG1 X1 Y2\nG90\nG1 U1 Y3", vec2(5.0, 5.0), 2.0, None), "G91\nG1 X2 Y4\nG90\nG1 U2 Y11");
+        let file = "G90.1\nG2 X10 Y0 I5 J0\nG91.1\nG2 X0 Y0 I-5 J0";
+        assert_eq!(transformed(file, vec2(1.0, 2.0), 1.0, None), "G90.1\nG2 X11 Y2 I6 J2\nG91.1\nG2 X1 Y2 I-5 J0");
+        // the distance mode of the line applies, wherever it stands
+        assert_eq!(transformed("G1 X5 G91", vec2(1.0, 0.0), 1.0, None), "G1 X5\nG91");
+    }
+
+    #[test]
+    fn transform_offsets() {
+        // the plotted position is scaled and shifted like the file
+        let (shift, scale) = (vec2(3.0, 4.0), 2.0);
+        for file in ["G0 X10 Y10\nG92 X0 Y0\nG1 X5 Y5", "G10 L2 P2 X100 Y50\nG55\nG0 X1 Y1", "G68 X10 Y0 R90\nG1 X20 Y0", "G0 X4 Y4\nG91\nG68 X1 Y1 R30\nG1 X2 Y1",
+            // the home and the start are not shifted
+            "G28\nG92 X0 Y0\nG1 X10 Y10", "G92 X0 Y0\nG1 X10 Y10", "G0 X5 Y5\nG28 X0\nG92 X1 Y1\nG1 X10 Y10", "G91\nG1 X2 Y3\nG92 X0\nG90\nG1 X10 Y10"] {
+            let expected = machine(file).pos * scale + shift;
+            let pos = machine(&transformed(file, shift, scale, None)).pos;
+            assert!(pos.distance(expected) < 1e-3, "`{file}` ends at {pos} instead of {expected}");
+        }
+    }
+
+    #[test]
+    fn transform_units() {
+        for (file, unit) in [("G20\nG1 X1 Y2 F10", Unit::Millimeter), ("G1 X25.4\nG20\nG1 Y1", Unit::Inch), ("G21\nG1 X25.4 Y50.8", Unit::Inch)] {
+            let converted = transformed(file, Vec2::ZERO, 1.0, Some(unit));
+            let end = machine(&converted);
+            assert!(end.pos.distance(machine(file).pos) < 1e-3, "`{file}` is converted to `{converted}`");
+            assert!(end.unit == unit);
+            // the unit is set before the first length
+            assert!(converted.starts_with(if unit == Unit::Inch { "G20\nG1" } else { "G21\nG1" }), "`{converted}` does not start with the unit");
+        }
+    }
+
+    #[test]
+    fn dwell() {
+        assert_eq!(machine("G4 P500").time, 0.5);
+        assert_eq!(machine("G4 S2\nG4 P250 S1").time, 3.25);
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("[1 + 2 * 3]"), 7.0);
+        assert_eq!(eval("[2 * 3 ** 2]"), 18.0);
+        assert_eq!(eval("[10 - 4 - 3]"), 3.0);
+        assert_eq!(eval("[7 MOD 3 + 1]"), 2.0);
+        assert_eq!(eval("[[1 + 2] * 3]"), 9.0);
+        assert_eq!(eval("[-2 ** 2]"), eval("[[-2] ** 2]"));
+        assert_eq!(eval("[1 + 1 EQ 2 AND 3 GT 2]"), 1.0);
+        assert_eq!(eval("[1 LT 0 OR 2 LE 1]"), 0.0);
+    }
+
+    #[test]
+    fn functions() {
+        assert!((eval("[ATAN[1]/[1]]") - 45.0).abs() < 1e-4);
+        assert!((eval("[ATAN[1]/[-1]]") - 135.0).abs() < 1e-4);
+        assert!((eval("[ATAN[0]/[1] + 1]") - 1.0).abs() < 1e-4);
+        assert!((eval("[SIN[30]]") - 0.5).abs() < 1e-4);
+        assert_eq!(eval("[FIX[-1.5]]"), -2.0);
+        assert_eq!(eval("[FUP[1.2]]"), 2.0);
+    }
+
+    #[test]
+    fn undefined_parameter() {
+        let (exprs, _) = parse::parse_gcode("#1 = [#2 + 1]");
+        let GCodeExpr::Assign(_, value, _) = &exprs[0].expr else { panic!("not an assignment") };
+        assert!(matches!(evaluate(value, &Parameters::new()), Err(Param::Numbered(2))));
+    }
+
+    #[test]
+    fn while_loop() {
+        let (commands, errors) = run("#1 = 0\nO1 while [#1 LT 3]\nG1 X#1\n#1 = [#1 + 1]\nO1 endwhile\n");
+        assert_eq!(commands, ["G1 X0", "G1 X1", "G1 X2"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn repeat_break_continue() {
+        let file = "#1 = 0\nO1 repeat [10]\n#1 = [#1 + 1]\nO2 if [#1 EQ 2]\nO1 continue\nO2 endif\nO3 if [#1 GT 3]\nO1 break\nO3 endif\nG1 X#1\nO1 endrepeat\nG0 X0\n";
+        let (commands, errors) = run(file);
+        assert_eq!(commands, ["G1 X1", "G1 X3", "G0 X0"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn branches() {
+        for (value, expected) in [(1, "G1 X1"), (2, "G1 X2"), (3, "G1 X3")] {
+            let file = format!("#1 = {value}\nO1 if [#1 EQ 1]\nG1 X1\nO1 elseif [#1 EQ 2]\nG1 X2\nO1 else\nG1 X3\nO1 endif\n");
+            let (commands, errors) = run(&file);
+            assert_eq!(commands, [expected]);
+            assert!(errors.is_empty());
+        }
+    }
+
+    #[test]
+    fn unmatched_control() {
+        let (commands, errors) = run("G1 X1\nO1 if [0]\nO1 else\nO1 else\nO1 endif\nG1 X2\n");
+        assert_eq!(commands, ["G1 X1"]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("not part of a complete block"));
+    }
+
+    #[test]
+    fn subprogram_repeats() {
+        let program = "O1\nG1 X1\nM99\n";
+        let (commands, errors) = run(&format!("M98 P1 L3\nM2\n{program}"));
+        assert_eq!(commands, ["G1 X1", "G1 X1", "G1 X1"]);
+        assert!(errors.is_empty());
+        let (commands, _) = run(&format!("M98 P1 L0\nM2\n{program}"));
+        assert!(commands.is_empty());
+        let (commands, errors) = run(&format!("M98 P1 L2000000\nM2\n{program}"));
+        assert_eq!(commands.len(), MAX_ITERATIONS);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains(&MAX_ITERATIONS.to_string()));
+    }
+
+    #[test]
+    fn skip_subprogram() {
+        let (commands, errors) = run("G1 X1\nO1\nG1 X2\nM99\nG1 X3\n");
+        assert_eq!(commands, ["G1 X1", "G1 X3"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn expand_unknown() {
+        let (commands, errors) = run("M106 S255\nG1 X1 E2\nT1\n#1 = 3\nG1 X#1 E#1\nM117 Layer #1 of [#1 + 1] ; text\n");
+        assert_eq!(commands, ["M106 S255", "G1 X1 E2", "T1", "G1 X3 E3", "M117 Layer 3 of 4"]);
+        assert!(errors.is_empty());
+        // each pass over a line stays apart
+        let (commands, _) = run("O1 repeat [2]\nG1 X1 E1\nO1 endrepeat\n");
+        assert_eq!(commands, ["G1 X1 E1", "G1 X1 E1"]);
+    }
+
+    #[test]
+    fn parametric_subprogram() {
+        let (commands, errors) = run("#1 = 1\nO1 G1 X#1\nM99\nM98 P1\nG0 X0\n");
+        assert_eq!(commands, ["G1 X1", "G0 X0"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn program_name() {
+        let file = "%\nO1000 (MAIN)\nG21 G90\nG1 X10 Y10\nM98 P2000\nM30\nO2000\nG1 X0\nM99\n%\n";
+        let (commands, errors) = run(file);
+        assert_eq!(commands, ["G21 G90", "G1 X10 Y10", "G1 X0"]);
+        assert!(errors.is_empty());
+        let (commands, errors) = run("O1\nG1 X1\n");
+        assert_eq!(commands, ["G1 X1"]);
+        assert!(errors.is_empty());
+        // without `M2`/`M30` the subprograms follow the main program
+        let (commands, errors) = run("O1000\nG1 X1\nM98 P2000\nO2000\nG1 X2\nM99\n");
+        assert_eq!(commands, ["G1 X1", "G1 X2"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn missing_return() {
+        let (commands, errors) = run("G1 X1\nM98 P2\nO1\nG1 X2\nO2\nG1 X3\nM99\nG1 X4\n");
+        assert_eq!(commands, ["G1 X1", "G1 X3", "G1 X4"]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("`O1`"));
+    }
+
+    #[test]
+    fn planes() {
+        let p = vec3(1.0, 2.0, 3.0);
+        assert_eq!(to_plane(Plane::XY, p), p);
+        assert_eq!(to_plane(Plane::ZX, p), vec3(3.0, 1.0, 2.0));
+        assert_eq!(to_plane(Plane::YZ, p), vec3(2.0, 3.0, 1.0));
+        for plane in [Plane::XY, Plane::ZX, Plane::YZ] {
+            assert_eq!(from_plane(plane, to_plane(plane, p)), p);
+        }
+    }
+
+    #[test]
+    fn views() {
+        assert_eq!(View::XY.mirrors(Plane::XY), Some(false));
+        assert_eq!(View::XZ.mirrors(Plane::ZX), Some(true));
+        assert_eq!(View::YZ.mirrors(Plane::YZ), Some(false));
+        assert_eq!(View::XY.mirrors(Plane::ZX), None);
+        assert_eq!(View::XZ.mirrors(Plane::YZ), None);
+        assert_eq!(View::XZ.project(vec3(1.0, 2.0, 3.0)), vec2(1.0, 3.0));
+    }
+
+    #[test]
+    fn radius_arcs() {
+        let (start, end) = (Vec3::ZERO, vec3(10.0, 0.0, 0.0));
+        let r = 50f32.sqrt();
+        // the short clockwise arc goes over the chord
+        let center = radius_arc_center(Plane::XY, start, end, r, true);
+        assert!(center.distance(vec3(5.0, -5.0, 0.0)) < 1e-4);
+        assert!((arc_angle(Plane::XY, start, end, center, true) - PI / 2.0).abs() < 1e-4);
+        // a negative radius gives the long arc with the center on the other side
+        let center = radius_arc_center(Plane::XY, start, end, -r, true);
+        assert!(center.distance(vec3(5.0, 5.0, 0.0)) < 1e-4);
+        assert!((arc_angle(Plane::XY, start, end, center, true) - 1.5 * PI).abs() < 1e-4);
+        let center = radius_arc_center(Plane::XY, start, end, r, false);
+        assert!(center.distance(vec3(5.0, 5.0, 0.0)) < 1e-4);
+        assert!((arc_angle(Plane::XY, start, end, center, false) - PI / 2.0).abs() < 1e-4);
+        // a too small radius gives a half circle
+        let center = radius_arc_center(Plane::XY, start, end, 1.0, true);
+        assert!(center.distance(vec3(5.0, 0.0, 0.0)) < 1e-4);
+        assert!((arc_angle(Plane::XY, start, start, vec3(5.0, 0.0, 0.0), true) - 2.0 * PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn position_offset() {
+        let shifted = machine("G0 X10 Y10\nG92 X0 Y0\nG0 X5");
+        assert_eq!(shifted.pos, vec2(15.0, 10.0));
+        assert_eq!(shifted.offset, vec2(10.0, 10.0));
+        assert_eq!(machine("G0 X10 Y10\nG92 X0 Y0\nG92.1\nG0 X5").pos, vec2(5.0, 10.0));
+    }
+
+    #[test]
+    fn laser_rapid_moves() {
+        let laser = machine("M3 S1000");
+        let rapid = CommentlessGCodeExpr::Move { X: Some(Coord::Modal(10.0)), Y: None, Z: None, F: None, S: None };
+        let linear = CommentlessGCodeExpr::LinMove { X: Some(Coord::Modal(10.0)), Y: None, Z: None, F: None, S: None };
+        assert!(!laser.draws(&rapid));
+        assert!(laser.draws(&linear));
+        assert!(machine("M280 P0 S50").draws(&rapid));
+    }
+
+    #[test]
+    fn home_axes() {
+        assert_eq!(machine("G1 X10 Y10\nG28 X0").pos, vec2(0.0, 10.0));
+        assert_eq!(machine("G1 X10 Y10\nG28 Y5").pos, vec2(10.0, 0.0));
+        assert_eq!(machine("G1 X10 Y10\nG28 Z0").pos, vec2(10.0, 10.0));
+        assert_eq!(machine("G1 X10 Y10\nG28").pos, Vec2::ZERO);
+    }
+
+    #[test]
+    fn coordinate_systems() {
+        let file = "G10 L2 P2 X100 Y50\nG55\nG0 X1 Y1";
+        assert_eq!(machine(file).pos, vec2(101.0, 51.0));
+        assert_eq!(machine(&format!("{file}\nG54\nG0 X1")).pos, vec2(1.0, 51.0));
+        // `P0` changes the active system
+        assert_eq!(machine("G56\nG10 L2 P0 X-5\nG0 X0 Y0").work_offsets[2], vec2(-5.0, 0.0));
+    }
+
+    #[test]
+    fn modal_groups() {
+        // the commands of a line run in the order of their groups
+        let (commands, _) = run("G1 X5 G91 M280 P0 S50");
+        assert_eq!(commands, ["M280 P0 S50 G91 G1 X5"]);
+        assert_eq!(machine("G0 X1\nG1 X5 G91").pos, vec2(6.0, 0.0));
+        let (commands, _) = run("#1 = 5\nG1 X#1 T1 G91");
+        assert_eq!(commands, ["G91 T1 G1 X5"]);
+        let (commands, _) = run("M2 G1 X1\nG1 X2");
+        assert_eq!(commands, ["G1 X1"]);
+        for line in ["G90 G91", "G0 X1 G1 X2", "G20 G21", "M0 M2"] {
+            assert_eq!(parse::parse_gcode(line).1.len(), 1, "`{line}` is accepted");
+        }
+    }
+
+    #[test]
+    fn float_range() {
+        for line in ["G1 X1e99", "G1 X1e-50", "G1 X-3.4e39"] {
+            assert_eq!(parse::parse_gcode(line).1.len(), 1, "`{line}` is accepted");
+        }
+        for line in ["G1 X1e30", "G1 X0e-50", "G1 X1.5e-5"] {
+            assert!(parse::parse_gcode(line).1.is_empty(), "`{line}` is rejected");
+        }
+    }
+}
@@ -4,9 +4,12 @@ use clap::{Parser, Subcommand, Args, ArgGroup};
 extern crate pest_derive;
 
 mod parse;
-use parse::CommentlessGCodeExpr;
+mod interpreter;
+use parse::{CommentlessGCodeExpr, Coord, Plane};
+use interpreter::{Machine, View};
 
 const DEBUG_MAX: u8 = 3;
+const DIAGNOSTICS_SHOWN: usize = 5;
 
 #[derive(Parser)]
 #[clap(version, author, about = "Draw simple gcode.", args_conflicts_with_subcommands = true)]
@@ -47,9 +50,24 @@ struct DisplayCliOptions {
     /// Sets the size of the small grid. The larger grid is always 5times as raw. While running, this can be changed with the key `G`.
     #[clap(short, long, value_parser, default_value_t = 10.0)]
     gridsize: f32,
+    /// Sets the `M280` servo angle `S`, from which on the pen is down.
+    #[clap(short, long = "pen-threshold", value_parser, default_value_t = interpreter::PEN_THRESHOLD)]
+    pen_threshold: f32,
+    /// Sets the height `Z`, up to which the pen is down.
+    #[clap(short, long = "z-threshold", value_parser, default_value_t = interpreter::Z_THRESHOLD, allow_hyphen_values = true)]
+    z_threshold: f32,
+    /// Sets the `S` of `M3`/`M4`, which is drawn with full intensity.
+    #[clap(long = "max-power", value_parser, default_value_t = 1000.0)]
+    max_power: f32,
     /// Enables hot reloading of the g-code file. Default is off. You can alternatively update the view with the key `R`.
     #[clap(long = "hot", action)]
-    hotreloading: bool
+    hotreloading: bool,
+    /// Colors the moves by their feed rate `F`, from blue (slow) to red (fast). This can be toggled while running with the key `F`.
+    #[clap(long = "feed-colors", action)]
+    feed_colors: bool,
+    /// Shows the plane `xy` (default), `xz` or `yz`. This can be changed while running with the key `V`.
+    #[clap(long, value_parser, default_value = "xy")]
+    view: View,
 }
 
 #[derive(Args)]
@@ -77,48 +95,115 @@ struct TransformCliOptions {
     /// Scale everything. (Note: scaling happens before translation.)
     #[clap(short = 'S', default_value_t = 1.0)]
     scale: f32,
+    /// Convert all lengths to `inch` or `mm` and set the unit with `G20`/`G21`. (Note: scaling and translation happen in the new unit.)
+    #[clap(long, value_parser)]
+    units: Option<parse::Unit>,
+    /// Change the line numbers with `add`, `renumber` or `strip`. Numbered lines get a new checksum.
+    #[clap(long = "line-numbers", value_parser)]
+    line_numbers: Option<parse::LineNumbering>,
+    /// Write the commands in the order in which they are run, with the parameters, loops, branches and subprogram calls resolved.
+    #[clap(long)]
+    expand: bool,
 }
 
 enum DrawMode { None, G0, G1, G2, G3 }
+
 struct AppSettings {
     filename: Option<String>,
     scale: f32,
     grid_size: f32,
     debug_lvl: u8,
     treshold: f32,
+    pen_threshold: f32,
+    z_threshold: f32,
+    max_power: f32,
     hotreloading: bool,
+    feed_colors: bool, // color the moves by their feed rate
+    view: View,
+    max_feed: f32, // the largest `F` in the file
+    z_range: (f32, f32), // the lowest and highest `Z` drawn with the pen down
     commands: Vec<(usize, CommentlessGCodeExpr)>,
+    diagnostics: Vec<String>, // problems with the file, shown in the overlay
+    warnings: Vec<String>, // unknown commands, which are skipped
     shift_pressed: bool,
     control_pressed: bool,
     mouse_pos: Option<Point2>,
     adding_commands: Vec<CommentlessGCodeExpr>,
     deleted_command: Option<CommentlessGCodeExpr>,
-    current_pos: Vec<Point2>,
+    machine_states: Vec<Machine>, // after the file and after each added command
     saved: bool,
     current_command: DrawMode,
     temp_point: Option<Point2>,
-    pen_mode: bool,
 }
 
 impl AppSettings {
     /// loads a gcode file to a vector of CommentlessGCodeExpr
     fn load_file(&mut self) {
         if let Some(filename) = &self.filename {
-            let file = std::fs::read_to_string(&filename).expect(&format!("Error opening `{}`.", &filename));
-            self.commands = parse::parse_gcode_file_commentless(&file).expect("problem parsing").iter()
-                .map(|(l, c)| match c {
-                    CommentlessGCodeExpr::Pen(_) => { self.pen_mode = !self.pen_mode; (*l, *c) },
-                    _ => (*l, *c)
-                }).collect();
-            for (_, c) in self.commands.iter().rev() {
-                match c {
-                    CommentlessGCodeExpr::Move { X: x, Y: y }
-                    | CommentlessGCodeExpr::Arc{ CLKW: _, X: x, Y: y, I: _, J: _ } => { self.current_pos = vec![pt2(*x, *y)]; break },
-                    _ => {}
+            let file = match std::fs::read_to_string(filename) {
+                Ok(file) => file,
+                Err(e) => {
+                    self.diagnostics = vec![format!("Error opening `{filename}`: {e}")];
+                    self.warnings.clear();
+                    return;
+                }
+            };
+            let (exprs, mut errors) = parse::parse_gcode(&file);
+            errors.append(&mut parse::check_line_numbers(&file, &exprs));
+            errors.sort_by_key(|e| e.line);
+            self.diagnostics = errors.iter().map(|e| e.to_string()).collect();
+            self.warnings = parse::unknown_commands(&file, &exprs).iter().map(|w| w.to_string()).collect();
+            let (commands, program_errors) = interpreter::expand(&exprs);
+            // the unknown commands are skipped
+            self.commands = commands.into_iter().flat_map(|(l, pass)| pass.into_iter().filter_map(move |cmd| match cmd {
+                parse::Command::Code(cmd) => Some((l, cmd)),
+                parse::Command::Unknown(_) => None,
+            })).collect();
+            self.diagnostics.extend(program_errors.iter().map(|e| e.to_string()));
+            let mut machine = Machine::new(self.pen_threshold, self.z_threshold);
+            self.max_feed = 0.0;
+            self.z_range = (f32::INFINITY, f32::NEG_INFINITY);
+            for (l, c) in &self.commands {
+                let before = machine;
+                machine.execute(c);
+                let problem = match before.resolve(c) {
+                    CommentlessGCodeExpr::RadiusArc { CLKW: clkw, R: r, .. } => radius_arc(&before, &machine, r, clkw, self.treshold).1,
+                    CommentlessGCodeExpr::Arc { I: i, J: j, K: k, .. } => arc_problem(&before, &machine, i, j, k, self.treshold),
+                    _ => None,
+                };
+                if let Some(problem) = problem {
+                    let problem = format!("Cannot draw arc in line {}, {problem}.", l + 1);
+                    // an arc in a loop is reported once
+                    if !self.diagnostics.contains(&problem) {
+                        self.diagnostics.push(problem);
+                    }
                 }
+                self.max_feed = self.max_feed.max(machine.feed.unwrap_or(0.0));
+                if machine.pen_down {
+                    self.z_range = (self.z_range.0.min(machine.z), self.z_range.1.max(machine.z));
+                }
+            }
+            self.machine_states = vec![machine];
+            for c in &self.adding_commands {
+                machine.execute(c);
+                self.machine_states.push(machine);
             }
         }
     }
+
+    /// the state of the plotter after all commands
+    fn machine(&self) -> &Machine {
+        self.machine_states.last().expect("there is always the state after the file")
+    }
+
+    /// appends a command to the added commands
+    fn add_command(&mut self, cmd: CommentlessGCodeExpr) {
+        let mut machine = *self.machine();
+        machine.execute(&cmd);
+        self.adding_commands.push(cmd);
+        self.machine_states.push(machine);
+        self.saved = false;
+    }
 }
 
 impl Default for AppSettings {
@@ -129,18 +214,26 @@ impl Default for AppSettings {
             grid_size: 10.0,
             debug_lvl: 0,
             treshold: 1e-5,
+            pen_threshold: interpreter::PEN_THRESHOLD,
+            z_threshold: interpreter::Z_THRESHOLD,
+            max_power: 1000.0,
             hotreloading: false,
+            feed_colors: false,
+            view: View::XY,
+            max_feed: 0.0,
+            z_range: (0.0, 0.0),
             commands: Vec::new(),
+            diagnostics: Vec::new(),
+            warnings: Vec::new(),
             shift_pressed: false,
             control_pressed: false,
             mouse_pos: None,
             adding_commands: Vec::new(),
             deleted_command: None,
-            current_pos: vec![Vec2::ZERO],
+            machine_states: vec![Machine::default()],
             saved: true,
             current_command: DrawMode::None,
             temp_point: None,
-            pen_mode: false, // at first is the pen up
         }
     }
 }
@@ -179,31 +272,60 @@ fn start_app(app: &App) -> AppSettings {
                 grid_size: subopts.gridsize,
                 debug_lvl: opts.debug,
                 treshold: subopts.treshold,
+                pen_threshold: subopts.pen_threshold,
+                z_threshold: subopts.z_threshold,
+                max_power: subopts.max_power,
                 hotreloading: subopts.hotreloading,
+                feed_colors: subopts.feed_colors,
+                view: subopts.view,
                 ..Default::default()
             };
             settings.load_file();
             settings
         },
         SubCommands::Transform(subopts) => {
-            let file = std::fs::read_to_string(&subopts.input).expect(&format!("Error opening `{}`.", subopts.input));
-            let commands = parse::parse_gcode_file(&file).expect("problem parsing");
-            let dx = subopts.x.unwrap_or(subopts.nx.unwrap_or(0.0));
-            let dy = subopts.y.unwrap_or(subopts.ny.unwrap_or(0.0));
-            let ds = subopts.scale;
-
-            let mut newcmds = Vec::with_capacity(commands.len());
-            for (l, cmd) in commands {
-                use parse::{GCodeExpr::*, CommentlessGCodeExpr::*};
-                newcmds.push((l,
-                    match cmd {
-                        Code(Move { X: x, Y: y }) => Code(Move { X: x * ds + dx, Y: y * ds + dy }),
-                        Code(LinMove { X: x, Y: y }) => Code(LinMove { X: x * ds + dx, Y: y * ds + dy }),
-                        Code(Arc { CLKW: clkw, X: x, Y: y, I: i, J: j }) => Code(Arc {
-                            CLKW: clkw, X: x * ds + dx, Y: y * ds + dy, I: i * ds, J: j * ds
-                        }),
-                        other => other,
-                    }))
+            // errors in the file end the program with a message instead of a panic
+            let file = std::fs::read_to_string(&subopts.input).unwrap_or_else(|_| {
+                eprintln!("Error opening `{}`.", subopts.input);
+                std::process::exit(1)
+            });
+            let commands = parse::parse_gcode_file(&file).unwrap_or_else(|e| {
+                eprintln!("Error parsing `{}`, {e}", subopts.input);
+                std::process::exit(1)
+            });
+            for warning in parse::unknown_commands(&file, &commands) {
+                println!("Warning, {warning}");
+            }
+            let expanded = if subopts.expand {
+                let (expanded, errors) = interpreter::expand(&commands);
+                if let Some(e) = errors.first() {
+                    eprintln!("Error expanding `{}`, {e}", subopts.input);
+                    std::process::exit(1)
+                }
+                expanded
+            } else {
+                Vec::new()
+            };
+            let commands: Vec<_> = if subopts.expand {
+                // each pass over a line gets its own line, since lines may be run several times
+                expanded.iter().enumerate().flat_map(|(l, (_, pass))| pass.iter().map(move |cmd| (l, match cmd {
+                    parse::Command::Code(cmd) => parse::GCodeExpr::Code(*cmd),
+                    parse::Command::Unknown(block) => parse::GCodeExpr::Unknown(block),
+                }))).collect()
+            } else {
+                commands.into_iter().map(|e| (e.line, e.expr)).collect()
+            };
+            let transformation = interpreter::Transformation {
+                shift: vec2(subopts.x.unwrap_or(subopts.nx.unwrap_or(0.0)), subopts.y.unwrap_or(subopts.ny.unwrap_or(0.0))),
+                scale: subopts.scale,
+                units: subopts.units,
+            };
+            let (mut newcmds, warnings) = interpreter::transform(commands, transformation);
+            for warning in warnings {
+                println!("Warning, {warning}");
+            }
+            if let Some(numbering) = subopts.line_numbers {
+                newcmds = parse::number_lines(newcmds, numbering);
             }
             parse::save(&format!("{}_transformed.gcode", subopts.input.strip_suffix(".gcode").expect("Expected gcode file")), newcmds);
 
@@ -224,12 +346,13 @@ fn view(app: &App, settings: &AppSettings, frame: Frame) {
     // set background to blue
     draw.background().color(WHITE);
 
-    draw_grid(&draw, &draw_area, settings.grid_size, settings.scale, 0.3, false);
-    draw_grid(&draw, &draw_area, 5.0 * settings.grid_size, settings.scale, 1.0, true);
+    draw_grid(&draw, &draw_area, settings.grid_size, settings.scale, 0.3, false, &[]);
+    let origins: &[Vec2] = if settings.view == View::XY { &settings.machine().work_offsets } else { &[] };
+    draw_grid(&draw, &draw_area, 5.0 * settings.grid_size, settings.scale, 1.0, true, origins);
 
-    draw_gcode(&draw, &draw_area, &settings);
+    draw_gcode(&draw, &draw_area, settings);
 
-    draw_overlay(&draw, &win, &settings);
+    draw_overlay(&draw, &win, settings);
 
     // put everything on the frame
     draw.to_frame(app, &frame).unwrap();
@@ -257,41 +380,32 @@ fn handle_keypress(app: &App, settings: &mut AppSettings, key: Key) {
             settings.grid_size = (100.0 * (settings.grid_size.round() + step)) / 100.0;
         }
         Key::Equals => { settings.scale += step },
-        Key::Minus => { if settings.scale > step { settings.scale -= step; } },
+        Key::Minus if settings.scale > step => { settings.scale -= step },
         Key::LShift | Key::RShift => { settings.shift_pressed = true },
         Key::LControl | Key::RControl => { settings.control_pressed = true },
         Key::Key0 => { settings.current_command = DrawMode::G0 },
         Key::Key1 => { settings.current_command = DrawMode::G1 },
         Key::Key2 => { settings.current_command = DrawMode::G2 },
         Key::Key3 => { settings.current_command = DrawMode::G3 },
-        Key::H => {
-            settings.adding_commands.push(CommentlessGCodeExpr::Home);
-            settings.current_pos.push(Vec2::ZERO);
-        }
+        Key::H => { settings.add_command(CommentlessGCodeExpr::Home { X: None, Y: None, Z: None }) }
         Key::Escape => { settings.current_command = DrawMode::None; settings.temp_point = None },
         Key::Z => {
             settings.deleted_command = settings.adding_commands.pop();
-            match settings.deleted_command {
-                Some(CommentlessGCodeExpr::Pen(_)) => { settings.pen_mode = !settings.pen_mode; },
-                Some(_) => { settings.current_pos.pop(); },
-                None => {},
-            } 
+            if settings.deleted_command.is_some() {
+                settings.machine_states.pop();
+            }
         }
         Key::Y => { if let Some(c) = settings.deleted_command {
-            settings.adding_commands.push(c);
-            match c {
-                CommentlessGCodeExpr::Home => settings.current_pos.push(Vec2::ZERO),
-                CommentlessGCodeExpr::Move { X: x, Y: y } | CommentlessGCodeExpr::LinMove { X: x, Y: y }
-                | CommentlessGCodeExpr::Arc{ CLKW: _, X: x, Y: y, I: _, J: _ } => settings.current_pos.push(pt2(x, y)),
-                CommentlessGCodeExpr::Pen(_) => { settings.pen_mode = !settings.pen_mode; }
-            }
+            settings.add_command(c);
             settings.deleted_command = None;
         }},
         Key::S => { parse::resave(settings.filename.as_deref(), &settings.adding_commands); settings.saved = true; },
         Key::P => {
-            settings.pen_mode = !settings.pen_mode;
-            settings.adding_commands.push(CommentlessGCodeExpr::Pen(settings.pen_mode));
+            let machine = settings.machine();
+            settings.add_command(machine.pen_command(!machine.pen_down));
         }
+        Key::F => { settings.feed_colors = !settings.feed_colors },
+        Key::V => { settings.view = settings.view.next(settings.shift_pressed) },
         Key::Q => { app.quit() }
         _ => {}
     }
@@ -318,27 +432,28 @@ fn handle_mouse_move(app: &App, settings: &mut AppSettings, pos: Point2) {
 
 fn handle_mouse_press(app: &App, settings: &mut AppSettings, button: MouseButton) {
     match button {
+        MouseButton::Left if settings.view != View::XY => println!("Commands can only be added in the XY view."),
         MouseButton::Left => if let Some(pos) = settings.mouse_pos {
             let (_, p) = get_grid_node(pos, &app.main_window().rect(), settings);
+            let machine = *settings.machine();
+            let words = machine.words(p);
+            // the added arcs are drawn in the XY plane
+            if matches!(settings.current_command, DrawMode::G2 | DrawMode::G3) && settings.temp_point.is_some() && machine.plane != Plane::XY {
+                settings.add_command(CommentlessGCodeExpr::Plane(Plane::XY));
+            }
             match settings.current_command {
                 DrawMode::G0 => {
-                    settings.adding_commands.push(CommentlessGCodeExpr::Move { X: p.x, Y: p.y });
-                    settings.current_pos.push(p);
-                    settings.saved = false;
+                    settings.add_command(CommentlessGCodeExpr::Move { X: Some(Coord::Modal(words.x)), Y: Some(Coord::Modal(words.y)), Z: None, F: None, S: None });
                 }
                 DrawMode::G1 => {
-                    settings.adding_commands.push(CommentlessGCodeExpr::LinMove { X: p.x, Y: p.y });
-                    settings.current_pos.push(p);
-                    settings.saved = false;
+                    settings.add_command(CommentlessGCodeExpr::LinMove { X: Some(Coord::Modal(words.x)), Y: Some(Coord::Modal(words.y)), Z: None, F: None, S: None });
                 },
                 DrawMode::G2 => {
                     if let Some(pos) = settings.temp_point {
-                        settings.adding_commands.push(CommentlessGCodeExpr::Arc {
-                            CLKW: true, X: pos.x, Y: pos.y,
-                            I: p.x - settings.current_pos.last().unwrap().x, J: p.y - settings.current_pos.last().unwrap().y
+                        let (end, center) = (machine.words(pos), machine.arc_words(p));
+                        settings.add_command(CommentlessGCodeExpr::Arc {
+                            CLKW: true, X: Some(Coord::Modal(end.x)), Y: Some(Coord::Modal(end.y)), Z: None, I: Some(center.x), J: Some(center.y), K: None, F: None, S: None
                         });
-                        settings.current_pos.push(pos);
-                        settings.saved = false;
                         settings.temp_point = None;
                     } else {
                         settings.temp_point = Some(p);
@@ -346,12 +461,10 @@ fn handle_mouse_press(app: &App, settings: &mut AppSettings, button: MouseButton
                 },
                 DrawMode::G3 => {
                     if let Some(pos) = settings.temp_point {
-                        settings.adding_commands.push(CommentlessGCodeExpr::Arc {
-                            CLKW: false, X: pos.x, Y: pos.y,
-                            I: p.x - settings.current_pos.last().unwrap().x, J: p.y - settings.current_pos.last().unwrap().y
+                        let (end, center) = (machine.words(pos), machine.arc_words(p));
+                        settings.add_command(CommentlessGCodeExpr::Arc {
+                            CLKW: false, X: Some(Coord::Modal(end.x)), Y: Some(Coord::Modal(end.y)), Z: None, I: Some(center.x), J: Some(center.y), K: None, F: None, S: None
                         });
-                        settings.current_pos.push(pos);
-                        settings.saved = false;
                         settings.temp_point = None;
                     } else {
                         settings.temp_point = Some(p);
@@ -362,7 +475,8 @@ fn handle_mouse_press(app: &App, settings: &mut AppSettings, button: MouseButton
         },
         MouseButton::Right => if let Some(pos) = settings.mouse_pos {
             let (_, p) = get_grid_node(pos, &app.main_window().rect(), settings);
-            println!("X{} Y{}", p.x, p.y);
+            let (h, v) = settings.view.axes();
+            println!("{h}{} {v}{}", p.x, p.y);
         }
         _ => {},
     }
@@ -371,114 +485,195 @@ fn handle_mouse_press(app: &App, settings: &mut AppSettings, button: MouseButton
 /// draw the gcode on the given window.
 fn draw_gcode(draw: &Draw, win: &Rect, settings: &AppSettings) {
     use CommentlessGCodeExpr::*;
-    let mut current = pt2(0.0, 0.0);
     let origin = vec2(win.left(), win.bottom());
-    let mut is_pen_down = false;
-    for (l, cmd) in settings.commands.iter().map(|i| (i.0, &i.1)).chain(settings.adding_commands.iter().enumerate()) {
+    let mut machine = Machine::new(settings.pen_threshold, settings.z_threshold);
+    for cmd in settings.commands.iter().map(|(_, cmd)| cmd).chain(settings.adding_commands.iter()) {
+        let cmd = &machine.resolve(cmd);
+        let before = machine;
+        let current = settings.view.project(before.pos3());
+        let is_pen_down = before.draws(cmd);
+        machine.execute(cmd);
+        let stroke = stroke_color(&machine, settings);
+        let p = settings.view.project(machine.pos3());
         match cmd {
-            Home => {
+            Home { .. } => {
                 if is_pen_down {
-                    draw.line().points(current * settings.scale + origin, origin).color(BLACK).weight(2.0);
+                    draw.line().points(current * settings.scale + origin, p * settings.scale + origin).color(stroke).weight(2.0);
                 } else if settings.debug_lvl > 0 {
-                    draw.line().points(current * settings.scale + origin, origin).rgb(0.7, 0.7, 0.7);
+                    draw.line().points(current * settings.scale + origin, p * settings.scale + origin).rgb(0.7, 0.7, 0.7);
                 }
-                current = Vec2::ZERO;
             },
-            Move {X: x, Y: y}  => {
-                let p = pt2(*x, *y);
+            Move { .. } => {
                 let diff = p - current;
                 let p_mid = diff.abs().min_element() * diff.signum() + current;
                 if settings.debug_lvl > 2 {
                     if is_pen_down {
-                        draw.arrow().points(current * settings.scale + origin, p_mid * settings.scale + origin).color(BLACK).weight(2.0).head_width(3.0);
-                        draw.arrow().points(p_mid * settings.scale + origin, p * settings.scale + origin).color(BLACK).weight(2.0);
+                        draw.arrow().points(current * settings.scale + origin, p_mid * settings.scale + origin).color(stroke).weight(2.0).head_width(3.0);
+                        draw.arrow().points(p_mid * settings.scale + origin, p * settings.scale + origin).color(stroke).weight(2.0);
                     } else {
                         draw.arrow().points(current * settings.scale + origin, p_mid * settings.scale + origin).rgb(0.7, 0.7, 0.7).head_width(2.0);
                         draw.arrow().points(p_mid * settings.scale + origin, p * settings.scale + origin).rgb(0.7, 0.7, 0.7).head_width(3.0);
                     }
                 } else {
                     if is_pen_down {
-                        draw.line().points(current * settings.scale + origin, p_mid * settings.scale + origin).color(BLACK).weight(2.0);
-                        draw.line().points(p_mid * settings.scale + origin, p * settings.scale + origin).color(BLACK).weight(2.0);
+                        draw.line().points(current * settings.scale + origin, p_mid * settings.scale + origin).color(stroke).weight(2.0);
+                        draw.line().points(p_mid * settings.scale + origin, p * settings.scale + origin).color(stroke).weight(2.0);
                     } else if settings.debug_lvl > 0 {
                         draw.line().points(current * settings.scale + origin, p_mid * settings.scale + origin).rgb(0.7, 0.7, 0.7);
                         draw.line().points(p_mid * settings.scale + origin, p * settings.scale + origin).rgb(0.7, 0.7, 0.7);
                     }
                 }
-                current = p;
             },
-            LinMove {X: x, Y: y}  => {
-                let p = pt2(*x, *y);
+            LinMove { .. } => {
                 if settings.debug_lvl > 2 {
                     if is_pen_down {
-                        draw.arrow().points(current * settings.scale + origin, p * settings.scale + origin).color(BLACK).weight(2.0);
+                        draw.arrow().points(current * settings.scale + origin, p * settings.scale + origin).color(stroke).weight(2.0);
                     } else {
                         draw.arrow().points(current * settings.scale + origin, p * settings.scale + origin).rgb(0.7, 0.7, 0.7).head_width(3.0);
                     }
                 } else {
                     if is_pen_down {
-                        draw.line().points(current * settings.scale + origin, p * settings.scale + origin).color(BLACK).weight(2.0);
+                        draw.line().points(current * settings.scale + origin, p * settings.scale + origin).color(stroke).weight(2.0);
                     } else if settings.debug_lvl > 0 {
                         draw.line().points(current * settings.scale + origin, p * settings.scale + origin).rgb(0.7, 0.7, 0.7);
                     }
                 }
-                current = p;
             },
-            Pen(down) => { is_pen_down = *down; },
-            Arc {CLKW: clkw, X: x, Y: y, I: i, J: j} => {
-                #[allow(non_snake_case)]
-                let B = pt2(*x, *y);
-                #[allow(non_snake_case)]
-                let C = pt2(*i, *j);
-                if settings.debug_lvl > 1 {
-                    let a = current * settings.scale + origin;
-                    let b = B * settings.scale + origin;
-                    let c = (current + C) * settings.scale + origin;
-                    draw.ellipse().xy(b).w_h(4.0, 4.0).color(BLACK);
-                    draw.line().points(a, c).color(RED).weight(0.3);
-                    draw.ellipse().xy(c).w_h(5.0, 5.0).color(RED);
-                    draw.line().points(c, b).color(RED).weight(0.3);
-                    draw.ellipse().xy(a).w_h(4.0, 4.0).color(BLACK);
-                }
-                let a = - C;
-                let r2 = a.length_squared();
-                let steps = ((r2.sqrt() * 3.6) as usize).min(18);
-                let translation = (current + C) * settings.scale + origin;
-                let anglestep = if B.distance_squared(current) < settings.treshold { // make circle
-                    2.0 * PI / steps as f32
-                } else {
-                    let b = a + B - current;
-                    if (r2 - b.length_squared()).abs() > settings.treshold {
-                        println!("Cannot draw arc in line {}, (I,J) is no center.", l + 1)
-                    }
-                    let mut anglediff = a.angle_between(b);
-                    if *clkw {
-                        if (a.rotate(anglediff) - b).length_squared() < settings.treshold { // rotate `a` in G3 direction
-                            anglediff = 2.0 * PI - anglediff;
-                        }
-                        -anglediff / steps as f32
-                    } else {
-                        if (a.rotate(-anglediff) - b).length_squared() < settings.treshold { // rotate `a` in G2 direction
-                            anglediff = 2.0 * PI - anglediff;
-                        }
-                        anglediff / steps as f32
-                    }
-                };
-                
-                let points = (0..=steps).map(|n| a.rotate(n as f32 * anglestep) * settings.scale + translation);
-                if is_pen_down {
-                    draw.polyline().weight(2.0).points(points).color(BLACK);
-                } else if settings.debug_lvl > 0 {
-                    draw.polyline().points(points).rgb(0.7, 0.7, 0.7);
-                }
-                current = B;
+            Dwell { .. } => {
+                draw.ellipse().xy(p * settings.scale + origin).w_h(7.0, 7.0).no_fill().stroke(ORANGE).stroke_weight(1.5);
+            },
+            Pause(optional) => {
+                let marker = p * settings.scale + origin;
+                draw.rect().xy(marker).w_h(6.0, 6.0).color(RED);
+                draw.text(if *optional { "M1" } else { "M0" }).xy(marker + vec2(10.0, 6.0)).w(20.0).color(RED);
+            },
+            Pen { .. } | Spindle { .. } | SpindleOff | Units(_) | Plane(_) | Distance(_) | ArcDistance(_) | Rotate { .. } | CancelRotation | SetPosition { .. } | ResetPosition
+            | CoordinateSystem(_) | SetOffset { .. }
+            | SubProgram(_) | Call { .. } | Return | ProgramEnd(_) | ModalMove { .. } => {},
+            Arc {CLKW: clkw, I: i, J: j, K: k, ..} => {
+                let center = before.arc_center(*i, *j, *k);
+                draw_plane_arc(draw, origin, settings, before.plane, before.pos3(), machine.pos3(), center, *clkw, is_pen_down, stroke);
             },
+            // the problems are reported when loading the file
+            RadiusArc {CLKW: clkw, R: r, ..} => if let (Some(center), _) = radius_arc(&before, &machine, *r, *clkw, settings.treshold) {
+                draw_plane_arc(draw, origin, settings, before.plane, before.pos3(), machine.pos3(), center, *clkw, is_pen_down, stroke);
+            },
+        }
+    }
+}
+
+/// the center of an `R` arc from the position `before` to the one `after` (relative to the start like `I`/`J`/`K`),
+/// and the problem, why it cannot be drawn as given. There is no arc between coinciding points
+fn radius_arc(before: &Machine, after: &Machine, r: f32, clkw: bool, treshold: f32) -> (Option<Vec3>, Option<&'static str>) {
+    let (start, end) = (before.pos3(), after.pos3());
+    let half_chord = interpreter::to_plane(before.plane, end - start).truncate().length() / 2.0;
+    if half_chord < treshold {
+        return (None, Some("start and end point coincide"));
+    }
+    let r = before.length(r);
+    let problem = (r.abs() + treshold < half_chord).then_some("R is smaller than half the distance to (X,Y)");
+    (Some(interpreter::radius_arc_center(before.plane, start, end, r, clkw)), problem)
+}
+
+/// the problem, why an arc from the position `before` to the one `after` has no center given by `I`, `J` and `K`.
+/// The center has to be equally far from both points, up to the treshold relative to the radius
+fn arc_problem(before: &Machine, after: &Machine, i: Option<f32>, j: Option<f32>, k: Option<f32>, treshold: f32) -> Option<&'static str> {
+    let center = before.arc_center(i, j, k);
+    let start = interpreter::to_plane(before.plane, center).truncate().length();
+    let end = interpreter::to_plane(before.plane, before.pos3() + center - after.pos3()).truncate().length();
+    let problem = match before.plane {
+        Plane::XY => "(I,J) is no center",
+        Plane::ZX => "(I,K) is no center",
+        Plane::YZ => "(J,K) is no center",
+    };
+    ((start - end).abs() > treshold * start.max(1.0)).then_some(problem)
+}
+
+/// the color of drawn lines, a laser is shown more transparent with less power.
+/// With feed colors slow moves are blue and the fastest ones red.
+/// While debugging higher moves are lighter, such that the depth `Z` is visible.
+fn stroke_color(machine: &Machine, settings: &AppSettings) -> Rgba {
+    let intensity = match machine.power {
+        Some(s) if machine.laser => (s / settings.max_power).clamp(0.0, 1.0),
+        _ => 1.0,
+    };
+    let color: Rgba = match machine.feed {
+        Some(f) if settings.feed_colors && settings.max_feed > 0.0 =>
+            hsla(2.0 / 3.0 * (1.0 - (f / settings.max_feed).clamp(0.0, 1.0)), 1.0, 0.4, intensity).into(),
+        _ => rgba(0.0, 0.0, 0.0, intensity),
+    };
+    let (low, high) = settings.z_range;
+    if settings.debug_lvl > 0 && high > low {
+        let shade = 0.6 * (machine.z - low) / (high - low);
+        rgba(color.red + (1.0 - color.red) * shade, color.green + (1.0 - color.green) * shade, color.blue + (1.0 - color.blue) * shade, color.alpha)
+    } else {
+        color
+    }
+}
+
+/// draws an arc in the plane from `start` to `end` around `start + center`,
+/// which is projected to the view if it shows another plane.
+#[allow(clippy::too_many_arguments)]
+fn draw_plane_arc(draw: &Draw, origin: Vec2, settings: &AppSettings, plane: Plane, start: Vec3, end: Vec3, center: Vec3, clkw: bool, is_pen_down: bool, stroke: Rgba) {
+    let view = settings.view;
+    if let Some(mirrored) = view.mirrors(plane) {
+        draw_arc(draw, origin, settings, view.project(start), view.project(end), view.project(center), clkw != mirrored, is_pen_down, stroke);
+        return;
+    }
+    let points = interpreter::arc_points(plane, start, end, center, clkw, 36).into_iter().map(|p| view.project(p) * settings.scale + origin);
+    if is_pen_down {
+        draw.polyline().weight(2.0).points(points).color(stroke);
+    } else if settings.debug_lvl > 0 {
+        draw.polyline().points(points).rgb(0.7, 0.7, 0.7);
+    }
+}
+
+/// draws an arc from `start` to `end` around `start + center` in the given direction.
+#[allow(non_snake_case, clippy::too_many_arguments)]
+fn draw_arc(draw: &Draw, origin: Vec2, settings: &AppSettings, start: Point2, B: Point2, C: Vec2, clkw: bool, is_pen_down: bool, stroke: Rgba) {
+    let current = start;
+    if settings.debug_lvl > 1 {
+        let a = current * settings.scale + origin;
+        let b = B * settings.scale + origin;
+        let c = (current + C) * settings.scale + origin;
+        draw.ellipse().xy(b).w_h(4.0, 4.0).color(BLACK);
+        draw.line().points(a, c).color(RED).weight(0.3);
+        draw.ellipse().xy(c).w_h(5.0, 5.0).color(RED);
+        draw.line().points(c, b).color(RED).weight(0.3);
+        draw.ellipse().xy(a).w_h(4.0, 4.0).color(BLACK);
+    }
+    let a = - C;
+    let r2 = a.length_squared();
+    let steps = ((r2.sqrt() * 3.6) as usize).min(18);
+    let translation = (current + C) * settings.scale + origin;
+    let anglestep = if B.distance_squared(current) < settings.treshold { // make circle
+        2.0 * PI / steps as f32
+    } else {
+        let b = a + B - current;
+        let mut anglediff = a.angle_between(b);
+        if clkw {
+            if (a.rotate(anglediff) - b).length_squared() < settings.treshold { // rotate `a` in G3 direction
+                anglediff = 2.0 * PI - anglediff;
+            }
+            -anglediff / steps as f32
+        } else {
+            if (a.rotate(-anglediff) - b).length_squared() < settings.treshold { // rotate `a` in G2 direction
+                anglediff = 2.0 * PI - anglediff;
+            }
+            anglediff / steps as f32
         }
+    };
+
+    let points = (0..=steps).map(|n| a.rotate(n as f32 * anglestep) * settings.scale + translation);
+    if is_pen_down {
+        draw.polyline().weight(2.0).points(points).color(stroke);
+    } else if settings.debug_lvl > 0 {
+        draw.polyline().points(points).rgb(0.7, 0.7, 0.7);
     }
 }
 
-/// creates a grid together with coordinate system
-fn draw_grid(draw: &Draw, win: &Rect, step: f32, scale: f32, weight: f32, make_axis: bool) {
+/// creates a grid together with coordinate system, which marks the `origins` of G54 to G59
+fn draw_grid(draw: &Draw, win: &Rect, step: f32, scale: f32, weight: f32, make_axis: bool, origins: &[Vec2]) {
     let step_by = || (0..).map(|i| i as f32 * step);
     let x_0 = win.left();
     let y_0 = win.bottom();
@@ -505,6 +700,13 @@ fn draw_grid(draw: &Draw, win: &Rect, step: f32, scale: f32, weight: f32, make_a
             .points(pt2(x_0, y_0), pt2(win.right() + 5.0, y_0));
         draw.line()
             .points(pt2(x_0, y_0), pt2(x_0, win.top() + 5.0));
+        // the origins of the coordinate systems, G54 is at the origin of the axes by default
+        for (i, origin) in origins.iter().enumerate().filter(|(_, o)| **o != Vec2::ZERO) {
+            let p = *origin * scale + pt2(x_0, y_0);
+            draw.line().points(p - pt2(5.0, 0.0), p + pt2(5.0, 0.0)).color(DARKGREEN).weight(1.5);
+            draw.line().points(p - pt2(0.0, 5.0), p + pt2(0.0, 5.0)).color(DARKGREEN).weight(1.5);
+            draw.text(&format!("G{}", 54 + i)).xy(p + pt2(14.0, -8.0)).w(30.0).color(DARKGREEN);
+        }
     }
 }
 
@@ -512,10 +714,19 @@ fn draw_grid(draw: &Draw, win: &Rect, step: f32, scale: f32, weight: f32, make_a
 fn draw_overlay(draw: &Draw, win: &Rect, settings: &AppSettings) {
     if let Some(pos) = settings.mouse_pos {
         let (pos, p) = get_grid_node(pos, win, settings);
-        draw.text(&format!("mouse: ({:.2}, {:.2})", p.x, p.y))
+        // in the unit active at the end of the file, like the words of added commands
+        let unit = settings.machine().unit;
+        let (h, v) = settings.view.axes();
+        draw.text(&format!("mouse: {h}{:.2} {v}{:.2} {}", p.x / unit.millimeters(), p.y / unit.millimeters(), unit.name()))
             .x_y(win.left() + 85.0, win.top() - 5.0).w(150.0).color(BLACK).left_justify();
+        // the work coordinates of `G92` and `G68` next to the cursor, if they differ
+        let work = settings.machine().work_point(p);
+        if settings.view == View::XY && work.distance(p) > settings.treshold {
+            draw.text(&format!("work: ({:.2}, {:.2})", work.x / unit.millimeters(), work.y / unit.millimeters()))
+                .xy(pos + pt2(90.0, 8.0)).w(150.0).color(DARKBLUE).left_justify();
+        }
         // draw crosshair
-        if settings.pen_mode {
+        if settings.machine().pen_down {
             draw.line().points(pos - pt2(3.0, 0.0), pos + pt2(3.0, 0.0));
             draw.line().points(pos - pt2(0.0, 3.0), pos + pt2(0.0, 3.0));
         } else {
@@ -549,6 +760,21 @@ fn draw_overlay(draw: &Draw, win: &Rect, settings: &AppSettings) {
         .x_y(win.left() + 520.0, win.top() - 5.0).w(150.0).color(BLACK).left_justify();
     draw.text(&format!("accuracy treshold: {}", settings.treshold))
         .x_y(win.left() + 670.0, win.top() - 5.0).w(200.0).color(BLACK).left_justify();
+    let time = settings.machine().time.round() as u32;
+    draw.text(&format!("estimated time: {}:{:02}:{:02}", time / 3600, time / 60 % 60, time % 60))
+        .x_y(win.right() - 110.0, win.top() - 25.0).w(200.0).color(BLACK).right_justify();
+    // problems with the file, followed by the warnings
+    let width = win.w() - 40.0;
+    let problems = settings.diagnostics.iter().map(|d| (d, RED)).chain(settings.warnings.iter().map(|w| (w, DARKORANGE)));
+    for (i, (problem, color)) in problems.take(DIAGNOSTICS_SHOWN).enumerate() {
+        draw.text(problem)
+            .x_y(win.left() + 30.0 + width / 2.0, win.top() - 25.0 - 12.0 * i as f32).w(width).color(color).left_justify();
+    }
+    let count = settings.diagnostics.len() + settings.warnings.len();
+    if count > DIAGNOSTICS_SHOWN {
+        draw.text(&format!("... and {} more problems", count - DIAGNOSTICS_SHOWN))
+            .x_y(win.left() + 30.0 + width / 2.0, win.top() - 25.0 - 12.0 * DIAGNOSTICS_SHOWN as f32).w(width).color(RED).left_justify();
+    }
 }
 
 /// calculates the nearest corresponding point on the grid. (nannou coords, plotter cords)
@@ -1,30 +1,226 @@
 extern crate pest;
 
-use pest::{ Parser, error::Error, iterators::Pair };
+use std::ops::Range;
+use pest::{ Parser, error::{Error, ErrorVariant, LineColLocation}, iterators::{Pair, Pairs} };
 
 /// handles all (limited) gcode
 #[allow(non_snake_case)]
 pub enum GCodeExpr<'a> {
     Code(CommentlessGCodeExpr),
-    Comment(&'a str),
+    Comment(CommentStyle, &'a str), // without the delimiters
+    InlineComment(&'a str, char), // a `(...)` between the words of the preceding command, after the word with this letter
+    LineNumber(u32), // N-word at the start of the line
+    Checksum(u32), // XOR of the line before the `*`, recalculated on save
+    Unknown(&'a str), // an unsupported command with its words, saved as it is
+    Parametric(ParametricBlock<'a>), // commands with parameters or expressions, evaluated while running
+    Assign(Param, Expression, &'a str), // `#1 = ...`, with the text to save
+    Control(u32, Control, &'a str), // `O100 while [...]` and the like, with the O-number and the text to save
 }
 
 /// without Comments, for faster and more memory efficient usecases
 #[allow(non_snake_case)]
 #[derive(Copy, Clone)]
 pub enum CommentlessGCodeExpr {
-    Home,
-    Move { X: f32, Y: f32 },
-    LinMove { X: f32, Y: f32 },
-    Arc { CLKW: bool, X: f32, Y: f32, I: f32, J: f32 },
-    Pen(bool), // true => PENDOWN
+    Home { X: Option<f32>, Y: Option<f32>, Z: Option<f32> }, // G28, the axis words choose the axes to home (all without them) and are kept as written
+    Move { X: Option<Coord>, Y: Option<Coord>, Z: Option<Coord>, F: Option<f32>, S: Option<f32> }, // omitted axes keep their value, F is the modal feed rate and S the modal power
+    LinMove { X: Option<Coord>, Y: Option<Coord>, Z: Option<Coord>, F: Option<f32>, S: Option<f32> },
+    Arc { CLKW: bool, X: Option<Coord>, Y: Option<Coord>, Z: Option<Coord>, I: Option<f32>, J: Option<f32>, K: Option<f32>, F: Option<f32>, S: Option<f32> }, // in the selected plane
+    RadiusArc { CLKW: bool, X: Option<Coord>, Y: Option<Coord>, Z: Option<Coord>, R: f32, F: Option<f32>, S: Option<f32> }, // R < 0 => arc larger than a half circle
+    ModalMove { X: Option<Coord>, Y: Option<Coord>, Z: Option<Coord>, I: Option<f32>, J: Option<f32>, K: Option<f32>, R: Option<f32>, F: Option<f32>, S: Option<f32> }, // without G-word, continues the last motion
+    Pen { P: u32, S: f32 }, // servo P at angle S, the pen is down above a threshold
+    Spindle { CLKW: bool, S: Option<f32> }, // M3/M4 for lasers and spindles, the pen is down with power S > 0
+    SpindleOff,
+    Dwell { P: Option<f32>, S: Option<f32> }, // G4, wait P milliseconds and S seconds as in Marlin
+    Pause(bool), // true => M1, the optional stop
+    Units(Unit),
+    Plane(Plane), // of the arcs
+    Distance(DistanceMode),
+    ArcDistance(DistanceMode), // how I and J are interpreted
+    Rotate { X: f32, Y: f32, R: f32 }, // R in degrees, anticlockwise around (X,Y)
+    CancelRotation,
+    SetPosition { X: Option<f32>, Y: Option<f32>, Z: Option<f32> }, // G92, the current position gets these coordinates, omitted axes keep their offset
+    ResetPosition, // G92.1, removes the offset of G92
+    CoordinateSystem(u32), // 1 => G54, ..., 6 => G59
+    SetOffset { P: u32, X: Option<f32>, Y: Option<f32> }, // G10 L2, the origin of coordinate system P (0 => the active one)
+    SubProgram(u32), // O-word, starts the subprogram with this number
+    Call { P: u32, L: u32 }, // call subprogram P, L times
+    Return,
+    ProgramEnd(bool), // true => M30
+}
+
+/// the value of an axis word, `X`/`Y` follow the distance mode and `U`/`V` are always incremental
+#[derive(Copy, Clone)]
+pub enum Coord {
+    Modal(f32),
+    Incremental(f32),
+}
+
+impl Coord {
+    /// scales the value and shifts it, unless it is incremental
+    pub fn transform(self, scale: f32, shift: f32) -> Coord {
+        match self {
+            Coord::Modal(v) => Coord::Modal(v * scale + shift),
+            Coord::Incremental(v) => Coord::Incremental(v * scale),
+        }
+    }
+
+    /// formats the word with the letter of the modal or the incremental axis
+    fn as_str(&self, modal: char, incremental: char) -> String {
+        match self {
+            Coord::Modal(v) => format!("{modal}{v}"),
+            Coord::Incremental(v) => format!("{incremental}{v}"),
+        }
+    }
+}
+
+/// formats an optional axis word with a leading space
+fn axis_str(c: &Option<Coord>, modal: char, incremental: char) -> String {
+    c.map(|c| format!(" {}", c.as_str(modal, incremental))).unwrap_or_default()
+}
+
+/// formats an optional word with a leading space
+fn word_str(letter: char, v: &Option<f32>) -> String {
+    v.map(|v| format!(" {letter}{v}")).unwrap_or_default()
+}
+
+/// the unit of all lengths
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Unit {
+    Inch, // G20
+    Millimeter, // G21
+}
+
+impl Unit {
+    /// the length of the unit in millimeters
+    pub fn millimeters(self) -> f32 {
+        match self {
+            Unit::Inch => 25.4,
+            Unit::Millimeter => 1.0,
+        }
+    }
+
+    /// the short name of the unit
+    pub fn name(self) -> &'static str {
+        match self {
+            Unit::Inch => "inch",
+            Unit::Millimeter => "mm",
+        }
+    }
+}
+
+impl std::str::FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inch" | "in" => Ok(Unit::Inch),
+            "mm" => Ok(Unit::Millimeter),
+            _ => Err(format!("`{s}` is no unit, use `inch` or `mm`")),
+        }
+    }
+}
+
+/// a numbered parameter like `#1` or a named one like `#<size>`, whose name is stored in lowercase
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Param {
+    Numbered(u32),
+    Named(String),
+}
+
+impl std::fmt::Display for Param {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Param::Numbered(n) => write!(f, "#{n}"),
+            Param::Named(name) => write!(f, "#<{name}>"),
+        }
+    }
+}
+
+/// a value given by parameters and `[...]`, which is evaluated while running
+pub enum Expression {
+    Number(f32),
+    Param(Param),
+    Negate(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+    Function(Function, Box<Expression>),
+    Atan(Box<Expression>, Box<Expression>), // `ATAN[y]/[x]`, in degrees
+}
+
+/// the binary operators of expressions, comparisons and logic give 1 for true and 0 for false
+#[derive(Copy, Clone)]
+pub enum Operator {
+    Add, Subtract, Multiply, Divide, Modulo, Power,
+    Equal, NotEqual, Greater, GreaterEqual, Less, LessEqual,
+    And, Or, Xor, // any value except 0 is true
+}
+
+/// the functions of expressions, angles are in degrees
+#[derive(Copy, Clone)]
+pub enum Function { Abs, Acos, Asin, Cos, Exp, Fix, Fup, Round, Ln, Sin, Sqrt, Tan }
+
+/// the lines of the control flow, which belong together by their O-number
+pub enum Control {
+    If(Expression),
+    ElseIf(Expression),
+    Else,
+    EndIf,
+    While(Expression),
+    EndWhile,
+    Repeat(Expression), // the body is run as often as the rounded down value
+    EndRepeat,
+    Break, // leaves the loop
+    Continue, // starts the next iteration of the loop
+}
+
+/// the commands of a line, whose words contain parameters or expressions
+pub struct ParametricBlock<'a> {
+    pub text: &'a str, // as written, saved as it is
+    pub values: Vec<(Range<usize>, Expression)>, // the positions in `text`, which are replaced by the values
+}
+
+/// a command as it is run, where unknown commands keep their words with the values of parameters filled in
+pub enum Command {
+    Code(CommentlessGCodeExpr),
+    Unknown(String),
+}
+
+/// how a comment is delimited
+#[derive(Copy, Clone)]
+pub enum CommentStyle {
+    Semicolon, // `;` until the end of the line
+    Parentheses, // `(...)`, also between words
+    Percent, // `%`, which marks the start and end of a program on tape
+}
+
+/// the plane of arcs, the axes are given in the order, in which arcs are clockwise as seen from the positive third axis
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Plane {
+    XY, // G17
+    ZX, // G18, with the center given by `I` and `K`
+    YZ, // G19, with the center given by `J` and `K`
+}
+
+/// how the coordinates of a move are interpreted
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum DistanceMode {
+    Absolute, // G90, G90.1
+    Incremental, // G91, G91.1, relative to the current position
 }
 
 impl GCodeExpr<'_> {
     pub fn as_str(&self) -> String {
         match self {
             GCodeExpr::Code(gcode) => gcode.as_str(),
-            GCodeExpr::Comment(s) => format!(";{s}"),
+            GCodeExpr::Comment(CommentStyle::Semicolon, s) => format!(";{s}"),
+            GCodeExpr::Comment(CommentStyle::Parentheses, s) => format!("({s})"),
+            GCodeExpr::Comment(CommentStyle::Percent, s) => format!("%{s}"),
+            GCodeExpr::InlineComment(s, _) => format!("({s})"),
+            GCodeExpr::LineNumber(n) => format!("N{n}"),
+            GCodeExpr::Checksum(c) => format!("*{c}"),
+            GCodeExpr::Unknown(block) => block.to_string(),
+            GCodeExpr::Parametric(block) => block.text.to_string(),
+            GCodeExpr::Assign(_, _, text) => text.to_string(),
+            GCodeExpr::Control(_, _, text) => text.to_string(),
         }
     }
 }
@@ -32,15 +228,73 @@ impl GCodeExpr<'_> {
 impl CommentlessGCodeExpr {
     pub fn as_str(&self) -> String {
         match self {
-            CommentlessGCodeExpr::Home => "G28".to_string(),
-            CommentlessGCodeExpr::Move{X: x, Y: y} => format!("G0 X{x} Y{y}"),
-            CommentlessGCodeExpr::LinMove{X: x, Y: y} => format!("G1 X{x} Y{y}"),
-            CommentlessGCodeExpr::Arc{CLKW: clkw, X: x, Y: y, I: i, J: j} => if *clkw {
-                format!("G2 X{x} Y{y} I{i} J{j}")
+            CommentlessGCodeExpr::Home{X: x, Y: y, Z: z} => format!("G28{}{}{}", word_str('X', x), word_str('Y', y), word_str('Z', z)),
+            CommentlessGCodeExpr::Move{X: x, Y: y, Z: z, F: f, S: s} => format!("G0{}{}{}{}{}",
+                axis_str(x, 'X', 'U'), axis_str(y, 'Y', 'V'), axis_str(z, 'Z', 'Z'), word_str('F', f), word_str('S', s)
+            ),
+            CommentlessGCodeExpr::LinMove{X: x, Y: y, Z: z, F: f, S: s} => format!("G1{}{}{}{}{}",
+                axis_str(x, 'X', 'U'), axis_str(y, 'Y', 'V'), axis_str(z, 'Z', 'Z'), word_str('F', f), word_str('S', s)
+            ),
+            CommentlessGCodeExpr::Arc{CLKW: clkw, X: x, Y: y, Z: z, I: i, J: j, K: k, F: f, S: s} => format!("{}{}{}{}{}{}{}{}{}",
+                if *clkw { "G2" } else { "G3" }, axis_str(x, 'X', 'U'), axis_str(y, 'Y', 'V'), axis_str(z, 'Z', 'Z'),
+                word_str('I', i), word_str('J', j), word_str('K', k), word_str('F', f), word_str('S', s)
+            ),
+            CommentlessGCodeExpr::RadiusArc{CLKW: clkw, X: x, Y: y, Z: z, R: r, F: f, S: s} => format!("{}{}{}{} R{r}{}{}",
+                if *clkw { "G2" } else { "G3" }, axis_str(x, 'X', 'U'), axis_str(y, 'Y', 'V'), axis_str(z, 'Z', 'Z'), word_str('F', f), word_str('S', s)
+            ),
+            CommentlessGCodeExpr::ModalMove{X: x, Y: y, Z: z, I: i, J: j, K: k, R: r, F: f, S: s} => format!("{}{}{}{}{}{}{}{}{}",
+                axis_str(x, 'X', 'U'), axis_str(y, 'Y', 'V'), axis_str(z, 'Z', 'Z'),
+                word_str('I', i), word_str('J', j), word_str('K', k), word_str('R', r), word_str('F', f), word_str('S', s)
+            ).trim_start().to_string(),
+            CommentlessGCodeExpr::Pen{P: p, S: s} => format!("M280 P{p} S{s}"),
+            CommentlessGCodeExpr::Spindle{CLKW: clkw, S: s} => format!("{}{}", if *clkw { "M3" } else { "M4" }, word_str('S', s)),
+            CommentlessGCodeExpr::SpindleOff => "M5".to_string(),
+            CommentlessGCodeExpr::Dwell{P: p, S: s} => format!("G4{}{}", word_str('P', p), word_str('S', s)),
+            CommentlessGCodeExpr::Pause(optional) => if *optional { "M1".to_string() } else { "M0".to_string() },
+            CommentlessGCodeExpr::Units(Unit::Inch) => "G20".to_string(),
+            CommentlessGCodeExpr::Units(Unit::Millimeter) => "G21".to_string(),
+            CommentlessGCodeExpr::Plane(Plane::XY) => "G17".to_string(),
+            CommentlessGCodeExpr::Plane(Plane::ZX) => "G18".to_string(),
+            CommentlessGCodeExpr::Plane(Plane::YZ) => "G19".to_string(),
+            CommentlessGCodeExpr::Distance(DistanceMode::Absolute) => "G90".to_string(),
+            CommentlessGCodeExpr::Distance(DistanceMode::Incremental) => "G91".to_string(),
+            CommentlessGCodeExpr::ArcDistance(DistanceMode::Absolute) => "G90.1".to_string(),
+            CommentlessGCodeExpr::ArcDistance(DistanceMode::Incremental) => "G91.1".to_string(),
+            CommentlessGCodeExpr::Rotate{X: x, Y: y, R: r} => format!("G68 X{x} Y{y} R{r}"),
+            CommentlessGCodeExpr::CancelRotation => "G69".to_string(),
+            CommentlessGCodeExpr::SetPosition{X: x, Y: y, Z: z} => format!("G92{}{}{}", word_str('X', x), word_str('Y', y), word_str('Z', z)),
+            CommentlessGCodeExpr::ResetPosition => "G92.1".to_string(),
+            CommentlessGCodeExpr::CoordinateSystem(p) => format!("G{}", 53 + p),
+            CommentlessGCodeExpr::SetOffset{P: p, X: x, Y: y} => format!("G10 L2 P{p}{}{}", word_str('X', x), word_str('Y', y)),
+            CommentlessGCodeExpr::SubProgram(p) => format!("O{p}"),
+            CommentlessGCodeExpr::Call{P: p, L: l} => if *l == 1 {
+                format!("M98 P{p}")
             } else {
-                format!("G3 X{x} Y{y} I{i} J{j}")
+                format!("M98 P{p} L{l}")
             },
-            CommentlessGCodeExpr::Pen(down) => if *down { "M280 P0 S50".to_string() } else { "M280 P0 S0".to_string() },
+            CommentlessGCodeExpr::Return => "M99".to_string(),
+            CommentlessGCodeExpr::ProgramEnd(rewind) => if *rewind { "M30".to_string() } else { "M2".to_string() },
+        }
+    }
+
+    /// the modal group, of which only one command may be given per line, and the execution order within a line
+    pub fn modal_group(&self) -> (&'static str, u8) {
+        match self {
+            CommentlessGCodeExpr::SubProgram(_) => ("program", 0),
+            CommentlessGCodeExpr::Pen{..} | CommentlessGCodeExpr::Spindle{..} | CommentlessGCodeExpr::SpindleOff => ("spindle", 1),
+            CommentlessGCodeExpr::Dwell{..} => ("non-modal", 2),
+            CommentlessGCodeExpr::Plane(_) => ("plane", 3),
+            CommentlessGCodeExpr::Units(_) => ("units", 4),
+            CommentlessGCodeExpr::CoordinateSystem(_) => ("coordinate system", 5),
+            CommentlessGCodeExpr::Rotate{..} | CommentlessGCodeExpr::CancelRotation => ("rotation", 6),
+            CommentlessGCodeExpr::Distance(_) => ("distance", 7),
+            CommentlessGCodeExpr::ArcDistance(_) => ("arc distance", 8),
+            CommentlessGCodeExpr::Home{..} | CommentlessGCodeExpr::SetPosition{..} | CommentlessGCodeExpr::ResetPosition
+                | CommentlessGCodeExpr::SetOffset{..} => ("non-modal", 9),
+            CommentlessGCodeExpr::Move{..} | CommentlessGCodeExpr::LinMove{..} | CommentlessGCodeExpr::Arc{..}
+                | CommentlessGCodeExpr::RadiusArc{..} | CommentlessGCodeExpr::ModalMove{..} => ("motion", 10),
+            CommentlessGCodeExpr::Pause(_) | CommentlessGCodeExpr::ProgramEnd(_)
+                | CommentlessGCodeExpr::Call{..} | CommentlessGCodeExpr::Return => ("stop", 11),
         }
     }
 }
@@ -49,191 +303,761 @@ impl CommentlessGCodeExpr {
 #[grammar = "gcode.pest"]
 struct GCodeParser;
 
-pub fn parse_gcode_file(file: &str) -> Result<Vec<(usize, GCodeExpr)>, Error<Rule>> {
-    let mut commands = Vec::new();
-    let gcode = GCodeParser::parse(Rule::file, file)?;
-    for (l, pair) in gcode.enumerate() {
-        match pair.as_rule() {
-            Rule::expr => {
-                for expr in pair.into_inner() {
-                    commands.push( (l, parse_expr( expr )) );
-                }
-            },
-            Rule::EOI | Rule::COMMENT => {}, // end of input and empty line
-            _ => unreachable!(),
+/// a line, which could not be parsed
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(line: usize, text: &str, error: Error<Rule>) -> Self {
+        let column = match error.line_col {
+            LineColLocation::Pos((_, c)) | LineColLocation::Span((_, c), _) => c,
+        };
+        let message = match &error.variant {
+            // no command matched, listing all of them is not helpful
+            ErrorVariant::ParsingError { positives, .. } if positives.contains(&Rule::HOME) => "unknown command".to_string(),
+            _ => error.renamed_rules(|rule| match rule {
+                Rule::EOI => "end of line".to_string(),
+                Rule::COMMENT => "comment".to_string(),
+                Rule::MILLISECONDS => "P".to_string(),
+                Rule::signed => "value".to_string(),
+                other => format!("{other:?}"),
+            }).variant.message().to_string(),
+        };
+        ParseError { line, column, text: text.to_string(), message }
+    }
+
+    /// a problem with an expression, which could be parsed
+    fn at(file: &str, e: &SpannedExpr, message: String) -> Self {
+        let line_start = file[..e.span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = file[line_start..].find('\n').map_or(file.len(), |i| line_start + i);
+        ParseError {
+            line: e.line,
+            column: e.span.start - line_start + 1,
+            text: file[line_start..line_end].to_string(),
+            message,
         }
     }
-    Ok(commands)
 }
 
-pub fn parse_gcode_file_commentless(file: &str) -> Result<Vec<(usize, CommentlessGCodeExpr)>, Error<Rule>> {
-    let mut commands = Vec::new();
-    let gcode = GCodeParser::parse(Rule::file, file)?;
-    for (l, pair) in gcode.enumerate() {
-        match pair.as_rule() {
-            Rule::expr => {
-                for expr in pair.into_inner() {
-                    if let Some(gcode) = parse_expr_commentless( expr ) {
-                        commands.push( (l, gcode) );
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}, column {}: {} in `{}`", self.line + 1, self.column, self.message, self.text.trim())
+    }
+}
+
+/// an expression together with its position in the source file
+pub struct SpannedExpr<'a> {
+    pub line: usize, // starting at 0
+    pub span: Range<usize>, // byte offsets into the file
+    pub expr: GCodeExpr<'a>,
+}
+
+/// parses the file and fails at the first invalid line
+pub fn parse_gcode_file(file: &str) -> Result<Vec<SpannedExpr<'_>>, ParseError> {
+    let (exprs, errors) = parse_gcode(file);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(exprs),
+    }
+}
+
+/// parses each line on its own, such that an invalid line does not stop the parsing
+pub fn parse_gcode(file: &str) -> (Vec<SpannedExpr<'_>>, Vec<ParseError>) {
+    let mut exprs = Vec::new();
+    let mut errors = Vec::new();
+    let mut offset = 0;
+    for (l, raw) in file.split_inclusive('\n').enumerate() {
+        let line = raw.strip_suffix('\n').map(|line| line.strip_suffix('\r').unwrap_or(line)).unwrap_or(raw);
+        let parsed = parse_line(line, l, offset);
+        match parsed {
+            Ok(mut line_exprs) => exprs.append(&mut line_exprs),
+            Err(error) => errors.push(ParseError::new(l, line, error)),
+        }
+        offset += raw.len();
+    }
+    (exprs, errors)
+}
+
+/// parses the items of a line, where `offset` is the position of the line in the file
+fn parse_line(line: &str, l: usize, offset: usize) -> Result<Vec<SpannedExpr<'_>>, Error<Rule>> {
+    let pairs = GCodeParser::parse(Rule::line, line)?;
+    let mut line_exprs = Vec::new();
+    for pair in pairs {
+        let line_pairs = match pair.as_rule() {
+            Rule::expr => pair.into_inner(),
+            Rule::COMMENT => Pairs::single(pair), // a line with only a comment
+            Rule::EOI => continue, // end of input
+            _ => unreachable!(),
+        };
+        let line_pairs: Vec<_> = line_pairs.collect();
+        let span = |pair: &Pair<Rule>| offset + pair.as_span().start()..offset + pair.as_span().end();
+        // commands with parameters or expressions are kept as a template, which is filled in while running.
+        // The `O` word stays apart, such that the subprogram is known before running
+        let is_command = |p: &Pair<Rule>| !matches!(p.as_rule(), Rule::LINENUMBER | Rule::CHECKSUM | Rule::COMMENT | Rule::ASSIGNMENT | Rule::CONTROL | Rule::SUBPROGRAM);
+        let commands: Vec<_> = line_pairs.iter().filter(|p| is_command(p)).collect();
+        let values: Vec<_> = commands.iter().flat_map(|p| find_values((*p).clone())).collect();
+        if let (Some(first), Some(last), false) = (commands.first(), commands.last(), values.is_empty()) {
+            let start = first.as_span().start();
+            if let Some(program) = line_pairs.iter().find(|p| p.as_rule() == Rule::SUBPROGRAM && p.as_span().start() > start) {
+                return Err(Error::new_from_span(
+                    ErrorVariant::CustomError { message: format!("`{}` has to be in front of the words with parameters", program.as_str().trim()) },
+                    program.as_span()
+                ));
+            }
+            let mut block = ParametricBlock { text: line[start..last.as_span().end()].trim_end(), values: Vec::new() };
+            for value in values {
+                let range = value.as_span().start() - start..value.as_span().end() - start;
+                block.values.push((range, parse_expression(value)?));
+            }
+            // the block takes the place of the first command
+            let mut block = Some(block);
+            for expr in line_pairs.iter() {
+                if !is_command(expr) {
+                    line_exprs.push(SpannedExpr { line: l, span: span(expr), expr: parse_expr(expr.clone())? });
+                } else if let Some(block) = block.take() {
+                    let span = offset + start..offset + last.as_span().end();
+                    line_exprs.push(SpannedExpr { line: l, span, expr: GCodeExpr::Parametric(block) });
+                }
+            }
+            continue;
+        }
+        // axis words without G-word belong to the motion command of the line, like the `X1` in `G1 G90 X1`
+        let motion = line_pairs.iter().position(|p| matches!(p.as_rule(), Rule::MOVE | Rule::LINEARMOVE | Rule::ARC))
+            .or_else(|| line_pairs.iter().position(|p| p.as_rule() == Rule::MODALMOVE));
+        let words: Vec<_> = line_pairs.iter().enumerate()
+            .filter(|(k, p)| p.as_rule() == Rule::MODALMOVE && Some(*k) != motion)
+            .flat_map(|(_, p)| inner(p.clone()))
+            .collect();
+        let mut groups: Vec<(&str, &str)> = Vec::new();
+        let mut axes: Vec<(bool, &str)> = Vec::new();
+        for (k, expr) in line_pairs.iter().enumerate() {
+            // comments between the words stay with the command, also those between the axis words merged into the motion
+            let commented: Vec<_> = match expr.as_rule() {
+                Rule::MODALMOVE if Some(k) != motion => Vec::new(),
+                _ if Some(k) == motion => line_pairs.iter().enumerate()
+                    .filter(|(n, p)| *n == k || p.as_rule() == Rule::MODALMOVE)
+                    .map(|(_, p)| p)
+                    .collect(),
+                _ => vec![expr],
+            };
+            let code = match expr.as_rule() {
+                Rule::MODALMOVE if Some(k) != motion => None,
+                _ if Some(k) == motion => Some(GCodeExpr::Code(parse_motion(expr.clone(), words.clone())?)),
+                _ => Some(parse_expr(expr.clone())?),
+            };
+            if let Some(GCodeExpr::Code(cmd)) = &code {
+                let group = cmd.modal_group().0;
+                if let Some((_, first)) = groups.iter().find(|(g, _)| *g == group) {
+                    return Err(Error::new_from_span(
+                        ErrorVariant::CustomError { message: format!("`{}` cannot be given together with `{first}`", expr.as_str().trim()) },
+                        expr.as_span()
+                    ));
+                }
+                groups.push((group, expr.as_str().trim()));
+                if let Some(non_modal) = uses_axes(cmd) {
+                    if let Some((_, first)) = axes.iter().find(|(other, _)| *other != non_modal) {
+                        return Err(Error::new_from_span(
+                            ErrorVariant::CustomError { message: format!("`{}` cannot be given together with `{first}`, as both use the axis words", expr.as_str().trim()) },
+                            expr.as_span()
+                        ));
                     }
+                    axes.push((non_modal, expr.as_str().trim()));
                 }
-            },
-            Rule::EOI | Rule::COMMENT => {}, // end of input and empty line
-            _ => unreachable!(),
+            }
+            if let Some(code) = code {
+                line_exprs.push(SpannedExpr { line: l, span: span(expr), expr: code });
+            }
+            for command in commented {
+                for comment in command.clone().into_inner().flatten().filter(|p| p.as_rule() == Rule::COMMENT) {
+                    let item = match parse_expr(comment.clone())? {
+                        GCodeExpr::Comment(CommentStyle::Parentheses, text) => GCodeExpr::InlineComment(text, word_before(command, &comment)),
+                        other => other, // `;` and `%` comments end the line
+                    };
+                    line_exprs.push(SpannedExpr { line: l, span: span(&comment), expr: item });
+                }
+            }
         }
     }
-    Ok(commands)
+    // the commands keep the order of the source, they are run in the order of their modal groups by the interpreter
+    Ok(line_exprs)
 }
 
-fn parse_expr(pair: Pair<Rule>) -> GCodeExpr {
-    match pair.as_rule() {
+/// the letter of the word of the command, after which the comment is written
+fn word_before(command: &Pair<Rule>, comment: &Pair<Rule>) -> char {
+    let is_word = |p: &Pair<Rule>| matches!(p.as_rule(),
+        Rule::X | Rule::Y | Rule::U | Rule::V | Rule::Z | Rule::I | Rule::J | Rule::K | Rule::R | Rule::F | Rule::S | Rule::P | Rule::L | Rule::MILLISECONDS
+    );
+    command.clone().into_inner().flatten()
+        .filter(|p| is_word(p) && p.as_span().end() <= comment.as_span().start())
+        .last()
+        .map_or(command.as_str(), |word| word.as_str())
+        .chars().next().unwrap_or_default().to_ascii_uppercase()
+}
+
+/// whether a command with axis words is non-modal (true) or a motion (false)
+fn uses_axes(cmd: &CommentlessGCodeExpr) -> Option<bool> {
+    use CommentlessGCodeExpr::*;
+    match *cmd {
+        Home { X: x, Y: y, Z: z } | SetPosition { X: x, Y: y, Z: z } => (x.is_some() || y.is_some() || z.is_some()).then_some(true),
+        SetOffset { X: x, Y: y, .. } => (x.is_some() || y.is_some()).then_some(true),
+        Move { X: x, Y: y, Z: z, .. } | LinMove { X: x, Y: y, Z: z, .. } | Arc { X: x, Y: y, Z: z, .. }
+            | RadiusArc { X: x, Y: y, Z: z, .. } | ModalMove { X: x, Y: y, Z: z, .. } => (x.is_some() || y.is_some() || z.is_some()).then_some(false),
+        _ => None,
+    }
+}
+
+fn parse_expr(pair: Pair<Rule>) -> Result<GCodeExpr, Error<Rule>> {
+    Ok(GCodeExpr::Code(match pair.as_rule() {
         Rule::HOME => {
-            GCodeExpr::Code(CommentlessGCodeExpr::Home)
-        },
-        Rule::MOVE => {
-            let mut values = (0f32, 0f32);
-            for var in pair.into_inner() {
+            let mut values = (None, None, None);
+            for var in inner(pair) {
                 match var.as_rule() {
-                    Rule::X => {
-                        values.0 = var.into_inner().as_str().parse::<f32>().unwrap();
-                    },
-                    Rule::Y => {
-                        values.1 = var.into_inner().as_str().parse::<f32>().unwrap();
-                    },
+                    Rule::X => set_once(&mut values.0, word_value(&var)?, &var)?,
+                    Rule::Y => set_once(&mut values.1, word_value(&var)?, &var)?,
+                    Rule::Z => set_once(&mut values.2, word_value(&var)?, &var)?,
                     _ => unreachable!(),
                 }
             }
-            GCodeExpr::Code(CommentlessGCodeExpr::Move { X: values.0, Y: values.1 })
+            CommentlessGCodeExpr::Home { X: values.0, Y: values.1, Z: values.2 }
         },
-        Rule::LINEARMOVE => {
-            let mut values = (0f32, 0f32);
-            for var in pair.into_inner() {
+        Rule::MOVE | Rule::LINEARMOVE | Rule::ARC | Rule::MODALMOVE => parse_motion(pair, Vec::new())?,
+        Rule::PEN  => {
+            let mut values = (0u32, 0f32);
+            for var in inner(pair) {
                 match var.as_rule() {
-                    Rule::X => {
-                        values.0 = var.into_inner().as_str().parse::<f32>().unwrap();
+                    Rule::P => {
+                        values.0 = word_uint(&var)?;
                     },
-                    Rule::Y => {
-                        values.1 = var.into_inner().as_str().parse::<f32>().unwrap();
+                    Rule::S => {
+                        values.1 = word_value(&var)?;
                     },
                     _ => unreachable!(),
                 }
             }
-            GCodeExpr::Code(CommentlessGCodeExpr::LinMove { X: values.0, Y: values.1 })
+            CommentlessGCodeExpr::Pen { P: values.0, S: values.1 }
         },
-        Rule::ARC  => {
-            let mut values = (false, 0f32, 0f32, 0f32, 0f32);
-            for var in pair.into_inner() {
+        Rule::ROTATE => {
+            let mut values = (0f32, 0f32, 0f32);
+            for var in inner(pair) {
                 match var.as_rule() {
-                    Rule::CLKW => { values.0 = true }
-                    Rule::ANTICLKW => { values.0 = false }
                     Rule::X => {
-                        values.1 = var.into_inner().as_str().parse::<f32>().unwrap();
+                        values.0 = word_value(&var)?;
                     },
                     Rule::Y => {
-                        values.2 = var.into_inner().as_str().parse::<f32>().unwrap();
-                    },
-                    Rule::I => {
-                        values.3 = var.into_inner().as_str().parse::<f32>().unwrap();
+                        values.1 = word_value(&var)?;
                     },
-                    Rule::J => {
-                        values.4 = var.into_inner().as_str().parse::<f32>().unwrap();
+                    Rule::R => {
+                        values.2 = word_value(&var)?;
                     },
                     _ => unreachable!(),
                 }
             }
-            GCodeExpr::Code(CommentlessGCodeExpr::Arc {CLKW: values.0, X: values.1, Y: values.2, I: values.3, J: values.4 })
+            CommentlessGCodeExpr::Rotate { X: values.0, Y: values.1, R: values.2 }
         },
-        Rule::PEN  => {
-            GCodeExpr::Code(CommentlessGCodeExpr::Pen( pair.into_inner().as_str().parse::<f32>().unwrap() >= 40.0 ))
+        Rule::SETPOSITION => {
+            let mut values = (None, None, None);
+            for var in inner(pair) {
+                match var.as_rule() {
+                    Rule::X => set_once(&mut values.0, word_value(&var)?, &var)?,
+                    Rule::Y => set_once(&mut values.1, word_value(&var)?, &var)?,
+                    Rule::Z => set_once(&mut values.2, word_value(&var)?, &var)?,
+                    _ => unreachable!(),
+                }
+            }
+            CommentlessGCodeExpr::SetPosition { X: values.0, Y: values.1, Z: values.2 }
         },
-        Rule::COMMENT => {
-            GCodeExpr::Comment(pair.into_inner().as_str())
+        Rule::RESETPOSITION => CommentlessGCodeExpr::ResetPosition,
+        Rule::COORDINATESYSTEM => {
+            // the span of the command also contains the comments after it
+            let system = inner(pair).next().expect("the command has a coordinate system");
+            CommentlessGCodeExpr::CoordinateSystem(system.as_str()[1..].parse::<u32>().expect("the grammar only allows G54 to G59") - 53)
         },
-        _ => unreachable!(),
-    }
-}
-
-fn parse_expr_commentless(pair: Pair<Rule>) -> Option<CommentlessGCodeExpr> {
-    match pair.as_rule() {
-        Rule::HOME => Some(CommentlessGCodeExpr::Home),
-        Rule::MOVE => {
-            let mut values = (0f32, 0f32);
-            for var in pair.into_inner() {
+        Rule::SETOFFSET => {
+            let span = pair.as_span();
+            let mut values = (None, None, None, None);
+            for var in inner(pair) {
                 match var.as_rule() {
-                    Rule::X => {
-                        values.0 = var.into_inner().as_str().parse::<f32>().unwrap();
-                    },
-                    Rule::Y => {
-                        values.1 = var.into_inner().as_str().parse::<f32>().unwrap();
-                    },
+                    Rule::L => set_once(&mut values.0, word_uint(&var)?, &var)?,
+                    Rule::P => set_once(&mut values.1, word_uint(&var)?, &var)?,
+                    Rule::X => set_once(&mut values.2, word_value(&var)?, &var)?,
+                    Rule::Y => set_once(&mut values.3, word_value(&var)?, &var)?,
                     _ => unreachable!(),
                 }
             }
-            Some(CommentlessGCodeExpr::Move { X: values.0, Y: values.1 })
+            // the grammar only matches `L2`
+            let message = match values.1 {
+                Some(p) if p <= 6 => None,
+                Some(p) => Some(format!("`P{p}` is no coordinate system, use `P1` (G54) to `P6` (G59) or `P0` for the active one")),
+                None => Some("`G10 L2` needs a coordinate system `P`".to_string()),
+            };
+            if let Some(message) = message {
+                return Err(Error::new_from_span(ErrorVariant::CustomError { message }, span));
+            }
+            CommentlessGCodeExpr::SetOffset { P: values.1.unwrap_or(0), X: values.2, Y: values.3 }
         },
-        Rule::LINEARMOVE => {
-            let mut values = (0f32, 0f32);
-            for var in pair.into_inner() {
+        Rule::SPINDLE => {
+            let mut values = (true, None);
+            for var in inner(pair) {
                 match var.as_rule() {
-                    Rule::X => {
-                        values.0 = var.into_inner().as_str().parse::<f32>().unwrap();
-                    },
-                    Rule::Y => {
-                        values.1 = var.into_inner().as_str().parse::<f32>().unwrap();
+                    Rule::SPINDLECLKW => { values.0 = true },
+                    Rule::SPINDLEANTICLKW => { values.0 = false },
+                    Rule::S => {
+                        values.1 = Some(word_value(&var)?);
                     },
                     _ => unreachable!(),
                 }
             }
-            Some(CommentlessGCodeExpr::LinMove { X: values.0, Y: values.1 })
+            CommentlessGCodeExpr::Spindle { CLKW: values.0, S: values.1 }
         },
-        Rule::ARC  => {
-            let mut values = (false, 0f32, 0f32, 0f32, 0f32);
-            for var in pair.into_inner() {
+        Rule::SPINDLEOFF => CommentlessGCodeExpr::SpindleOff,
+        Rule::DWELL => {
+            let mut values = (None, None);
+            for var in inner(pair) {
                 match var.as_rule() {
-                    Rule::CLKW => { values.0 = true }
-                    Rule::ANTICLKW => { values.0 = false }
-                    Rule::X => {
-                        values.1 = var.into_inner().as_str().parse::<f32>().unwrap();
-                    },
-                    Rule::Y => {
-                        values.2 = var.into_inner().as_str().parse::<f32>().unwrap();
-                    },
-                    Rule::I => {
-                        values.3 = var.into_inner().as_str().parse::<f32>().unwrap();
+                    Rule::MILLISECONDS => set_once(&mut values.0, word_value(&var)?, &var)?,
+                    Rule::S => set_once(&mut values.1, word_value(&var)?, &var)?,
+                    _ => unreachable!(),
+                }
+            }
+            CommentlessGCodeExpr::Dwell { P: values.0, S: values.1 }
+        },
+        Rule::PAUSE => CommentlessGCodeExpr::Pause(
+            inner(pair).next().map(|stop| stop.as_rule()) == Some(Rule::OPTIONALSTOP)
+        ),
+        Rule::CANCELROTATION => CommentlessGCodeExpr::CancelRotation,
+        Rule::SUBPROGRAM => CommentlessGCodeExpr::SubProgram( word_uint(&pair)? ),
+        Rule::CALL => {
+            let mut values = (0u32, 1u32);
+            for var in inner(pair) {
+                match var.as_rule() {
+                    Rule::P => {
+                        values.0 = word_uint(&var)?;
                     },
-                    Rule::J => {
-                        values.4 = var.into_inner().as_str().parse::<f32>().unwrap();
+                    Rule::L => {
+                        values.1 = word_uint(&var)?;
                     },
                     _ => unreachable!(),
                 }
             }
-            Some(CommentlessGCodeExpr::Arc {CLKW: values.0, X: values.1, Y: values.2, I: values.3, J: values.4 })
+            CommentlessGCodeExpr::Call { P: values.0, L: values.1 }
+        },
+        Rule::RETURN => CommentlessGCodeExpr::Return,
+        Rule::PROGRAMEND => CommentlessGCodeExpr::ProgramEnd(inner(pair).next().map(|end| end.as_rule()) == Some(Rule::REWIND)),
+        Rule::UNITS => match inner(pair).next().map(|unit| unit.as_rule()) {
+            Some(Rule::INCH) => CommentlessGCodeExpr::Units(Unit::Inch),
+            Some(Rule::MILLIMETER) => CommentlessGCodeExpr::Units(Unit::Millimeter),
+            _ => unreachable!(),
+        },
+        Rule::PLANE => match inner(pair).next().map(|plane| plane.as_rule()) {
+            Some(Rule::XYPLANE) => CommentlessGCodeExpr::Plane(Plane::XY),
+            Some(Rule::ZXPLANE) => CommentlessGCodeExpr::Plane(Plane::ZX),
+            Some(Rule::YZPLANE) => CommentlessGCodeExpr::Plane(Plane::YZ),
+            _ => unreachable!(),
+        },
+        Rule::DISTANCE => CommentlessGCodeExpr::Distance( parse_distance_mode(pair) ),
+        Rule::ARCDISTANCE => CommentlessGCodeExpr::ArcDistance( parse_distance_mode(pair) ),
+        Rule::COMMENT => {
+            let comment = pair.as_str();
+            return Ok(match &comment[..1] {
+                ";" => GCodeExpr::Comment(CommentStyle::Semicolon, &comment[1..]),
+                "(" => GCodeExpr::Comment(CommentStyle::Parentheses, &comment[1..comment.len() - 1]),
+                "%" => GCodeExpr::Comment(CommentStyle::Percent, &comment[1..]),
+                _ => unreachable!(),
+            })
+        },
+        Rule::LINENUMBER => return Ok(GCodeExpr::LineNumber( word_uint(&pair)? )),
+        Rule::CHECKSUM => return Ok(GCodeExpr::Checksum( word_uint(&pair)? )),
+        Rule::UNKNOWN => return Ok(GCodeExpr::Unknown( pair.as_str().trim_end() )),
+        Rule::ASSIGNMENT => {
+            let text = pair.as_str().trim_end();
+            let mut words = inner(pair);
+            let param = parse_parameter(words.next().expect("an assignment has a parameter"))?;
+            let value = parse_expression(words.next().expect("an assignment has a value"))?;
+            return Ok(GCodeExpr::Assign(param, value, text))
+        },
+        Rule::CONTROL => {
+            let text = pair.as_str().trim_end();
+            let mut words = inner(pair);
+            let number = uint(&words.next().expect("a control line has an O-number"))?;
+            let line = words.next().expect("a control line has a keyword");
+            let rule = line.as_rule();
+            let condition = || parse_expression(inner(line.clone()).next().expect("the keyword is followed by an expression"));
+            let control = match rule {
+                Rule::IF => Control::If(condition()?),
+                Rule::ELSEIF => Control::ElseIf(condition()?),
+                Rule::ELSE => Control::Else,
+                Rule::ENDIF => Control::EndIf,
+                Rule::WHILE => Control::While(condition()?),
+                Rule::ENDWHILE => Control::EndWhile,
+                Rule::REPEAT => Control::Repeat(condition()?),
+                Rule::ENDREPEAT => Control::EndRepeat,
+                Rule::BREAK => Control::Break,
+                Rule::CONTINUE => Control::Continue,
+                _ => unreachable!(),
+            };
+            return Ok(GCodeExpr::Control(number, control, text))
         },
-        Rule::PEN  => Some(CommentlessGCodeExpr::Pen( pair.into_inner().as_str().parse::<f32>().unwrap() >= 40.0 )),
-        Rule::COMMENT => None,
         _ => unreachable!(),
+    }))
+}
+
+/// parses the words of a motion command and further axis words of its line, each of which may be given at most once
+fn parse_motion<'i>(pair: Pair<'i, Rule>, words: Vec<Pair<'i, Rule>>) -> Result<CommentlessGCodeExpr, Error<Rule>> {
+    let rule = pair.as_rule();
+    let span = pair.as_span();
+    let mut clkw = false;
+    let (mut x, mut y, mut z) = (None, None, None);
+    let (mut i, mut j, mut k, mut r, mut f, mut s) = (None, None, None, None, None, None);
+    for var in inner(pair).chain(words) {
+        match var.as_rule() {
+            Rule::CLKW => { clkw = true },
+            Rule::ANTICLKW => { clkw = false },
+            Rule::X => set_once(&mut x, Coord::Modal(word_value(&var)?), &var)?,
+            Rule::Y => set_once(&mut y, Coord::Modal(word_value(&var)?), &var)?,
+            Rule::U => set_once(&mut x, Coord::Incremental(word_value(&var)?), &var)?,
+            Rule::V => set_once(&mut y, Coord::Incremental(word_value(&var)?), &var)?,
+            Rule::Z => set_once(&mut z, Coord::Modal(word_value(&var)?), &var)?,
+            Rule::I => set_once(&mut i, word_value(&var)?, &var)?,
+            Rule::J => set_once(&mut j, word_value(&var)?, &var)?,
+            Rule::K => set_once(&mut k, word_value(&var)?, &var)?,
+            Rule::R => set_once(&mut r, word_value(&var)?, &var)?,
+            Rule::F => set_once(&mut f, word_value(&var)?, &var)?,
+            Rule::S => set_once(&mut s, word_value(&var)?, &var)?,
+            _ => unreachable!(),
+        }
     }
+    Ok(match (rule, r) {
+        (Rule::MOVE, _) => CommentlessGCodeExpr::Move { X: x, Y: y, Z: z, F: f, S: s },
+        (Rule::LINEARMOVE, _) => CommentlessGCodeExpr::LinMove { X: x, Y: y, Z: z, F: f, S: s },
+        (Rule::ARC, Some(_)) if i.is_some() || j.is_some() || k.is_some() => return Err(Error::new_from_span(
+            ErrorVariant::CustomError { message: "an arc is either given by `I`, `J` and `K` or by `R`".to_string() },
+            span
+        )),
+        (Rule::ARC, Some(r)) => CommentlessGCodeExpr::RadiusArc { CLKW: clkw, X: x, Y: y, Z: z, R: r, F: f, S: s },
+        (Rule::ARC, None) => CommentlessGCodeExpr::Arc { CLKW: clkw, X: x, Y: y, Z: z, I: i, J: j, K: k, F: f, S: s },
+        (Rule::MODALMOVE, _) => CommentlessGCodeExpr::ModalMove { X: x, Y: y, Z: z, I: i, J: j, K: k, R: r, F: f, S: s },
+        _ => unreachable!(),
+    })
 }
 
-/// saves commands to gcode
-pub fn save(filename: &str, commands: Vec<(usize, GCodeExpr)>) {
-    let mut last_l = 0;
-    std::fs::write(
-        filename,
-        commands.iter().map(|(l, cmd)| (l, cmd.as_str())).fold("".to_string(), |mut res, (l, s)| {
-            if last_l != *l {
-                res.push('\n')
+/// the number of a word like `X10`, which has to be representable as finite 32 bit float
+fn word_value(pair: &Pair<Rule>) -> Result<f32, Error<Rule>> {
+    number(&inner(pair.clone()).next().expect("a word contains a number"))
+}
+
+/// a number, which has to fit into a 32 bit float
+fn number(num: &Pair<Rule>) -> Result<f32, Error<Rule>> {
+    let value = num.as_str().parse::<f32>().ok().filter(|v| v.is_finite());
+    let mantissa = num.as_str().split(['e', 'E']).next().unwrap_or_default();
+    match value {
+        // a number like `1e-50` would silently become 0
+        Some(v) if v == 0.0 && mantissa.contains(|c: char| c.is_ascii_digit() && c != '0') => Err(Error::new_from_span(
+            ErrorVariant::CustomError { message: format!("`{}` is too small for a 32 bit float", num.as_str()) },
+            num.as_span()
+        )),
+        Some(v) => Ok(v),
+        None => Err(Error::new_from_span(
+            ErrorVariant::CustomError { message: format!("`{}` is too large for a 32 bit float", num.as_str()) },
+            num.as_span()
+        )),
+    }
+}
+
+/// the number of a word like `P1000`, which has to fit into 32 bit
+fn word_uint(pair: &Pair<Rule>) -> Result<u32, Error<Rule>> {
+    uint(&inner(pair.clone()).next().expect("a word contains a number"))
+}
+
+/// an unsigned number, which has to fit into 32 bit
+fn uint(num: &Pair<Rule>) -> Result<u32, Error<Rule>> {
+    num.as_str().parse::<u32>().map_err(|_| Error::new_from_span(
+        ErrorVariant::CustomError { message: format!("`{}` is too large for a 32 bit integer", num.as_str()) },
+        num.as_span()
+    ))
+}
+
+/// the outermost parameters and expressions in the words of a command
+fn find_values(pair: Pair<Rule>) -> Vec<Pair<Rule>> {
+    match pair.as_rule() {
+        Rule::parameter | Rule::expression => vec![pair],
+        _ => pair.into_inner().flat_map(find_values).collect(),
+    }
+}
+
+fn parse_parameter(pair: Pair<Rule>) -> Result<Param, Error<Rule>> {
+    let id = pair.into_inner().next().expect("a parameter has a number or a name");
+    Ok(match id.as_rule() {
+        Rule::uint => Param::Numbered(uint(&id)?),
+        Rule::name => Param::Named(id.as_str().to_lowercase()),
+        _ => unreachable!(),
+    })
+}
+
+fn parse_expression(pair: Pair<Rule>) -> Result<Expression, Error<Rule>> {
+    match pair.as_rule() {
+        Rule::num => return Ok(Expression::Number(number(&pair)?)),
+        Rule::parameter => return Ok(Expression::Param(parse_parameter(pair)?)),
+        _ => {},
+    }
+    let rule = pair.as_rule();
+    let text = pair.as_str();
+    let mut terms = inner(pair);
+    let mut term = || terms.next().expect("an expression is not empty");
+    Ok(match rule {
+        Rule::expression => parse_expression(term())?,
+        // operators of the same precedence are evaluated from left to right
+        Rule::logic | Rule::comparison | Rule::sum | Rule::product | Rule::power => {
+            let mut left = parse_expression(term())?;
+            while let Some(next) = terms.next() {
+                let (operator, right) = match next.as_rule() {
+                    Rule::add => (Operator::Add, terms.next()),
+                    Rule::subtract => (Operator::Subtract, terms.next()),
+                    Rule::multiply => (Operator::Multiply, terms.next()),
+                    Rule::divide => (Operator::Divide, terms.next()),
+                    Rule::modulo => (Operator::Modulo, terms.next()),
+                    Rule::eq => (Operator::Equal, terms.next()),
+                    Rule::ne => (Operator::NotEqual, terms.next()),
+                    Rule::gt => (Operator::Greater, terms.next()),
+                    Rule::ge => (Operator::GreaterEqual, terms.next()),
+                    Rule::lt => (Operator::Less, terms.next()),
+                    Rule::le => (Operator::LessEqual, terms.next()),
+                    Rule::and => (Operator::And, terms.next()),
+                    Rule::or => (Operator::Or, terms.next()),
+                    Rule::xor => (Operator::Xor, terms.next()),
+                    _ => (Operator::Power, Some(next)),
+                };
+                let right = parse_expression(right.expect("an operator is followed by a term"))?;
+                left = Expression::Binary(operator, Box::new(left), Box::new(right));
+            }
+            left
+        },
+        Rule::signed => match term() {
+            negate if negate.as_rule() == Rule::negate => Expression::Negate(Box::new(parse_expression(term())?)),
+            value => parse_expression(value)?,
+        },
+        Rule::atan => Expression::Atan(Box::new(parse_expression(term())?), Box::new(parse_expression(term())?)),
+        Rule::function => {
+            let function = match text.split('[').next().unwrap_or_default().trim().to_uppercase().as_str() {
+                "ABS" => Function::Abs,
+                "ACOS" => Function::Acos,
+                "ASIN" => Function::Asin,
+                "COS" => Function::Cos,
+                "EXP" => Function::Exp,
+                "FIX" => Function::Fix,
+                "FUP" => Function::Fup,
+                "ROUND" => Function::Round,
+                "LN" => Function::Ln,
+                "SIN" => Function::Sin,
+                "SQRT" => Function::Sqrt,
+                "TAN" => Function::Tan,
+                _ => unreachable!(),
             };
-            last_l = *l;
-            res.push_str(&s);
-            res
-        })
-        //collect::<Vec<String>>().join("\n")
-    ).expect("Unable to save the gcode.");
+            Expression::Function(function, Box::new(parse_expression(term())?))
+        },
+        _ => unreachable!(),
+    })
+}
+
+/// parses the commands of a parametric block, after the values were put in place of the parameters and expressions
+pub fn parse_block(block: &ParametricBlock, l: usize, values: &[f32]) -> Result<Vec<Command>, ParseError> {
+    let mut text = String::new();
+    let mut last = 0;
+    for ((range, _), value) in block.values.iter().zip(values) {
+        text.push_str(&block.text[last..range.start]);
+        text.push_str(&value.to_string());
+        last = range.end;
+    }
+    text.push_str(&block.text[last..]);
+    let exprs = parse_line(&text, l, 0).map_err(|e| ParseError::new(l, &text, e))?;
+    Ok(exprs.into_iter().filter_map(|e| match e.expr {
+        GCodeExpr::Code(cmd) => Some(Command::Code(cmd)),
+        GCodeExpr::Unknown(block) => Some(Command::Unknown(block.to_string())),
+        _ => None,
+    }).collect())
+}
+
+/// the inner pairs without the comments, which may be put between the words
+fn inner(pair: Pair<Rule>) -> impl Iterator<Item = Pair<Rule>> {
+    pair.into_inner().filter(|p| p.as_rule() != Rule::COMMENT)
+}
+
+/// stores the value of a word and fails, if the word (or its axis) was already given
+fn set_once<T>(word: &mut Option<T>, value: T, pair: &Pair<Rule>) -> Result<(), Error<Rule>> {
+    if word.is_some() {
+        return Err(Error::new_from_span(
+            ErrorVariant::CustomError { message: format!("`{}` repeats an already given word", pair.as_str().trim()) },
+            pair.as_span()
+        ));
+    }
+    *word = Some(value);
+    Ok(())
+}
+
+fn parse_distance_mode(pair: Pair<Rule>) -> DistanceMode {
+    match inner(pair).next().map(|mode| mode.as_rule()) {
+        Some(Rule::ABSOLUTE | Rule::ABSOLUTEARC) => DistanceMode::Absolute,
+        Some(Rule::INCREMENTAL | Rule::INCREMENTALARC) => DistanceMode::Incremental,
+        _ => unreachable!(),
+    }
+}
+
+/// the unknown commands, which are skipped when plotting and kept when saving
+pub fn unknown_commands(file: &str, exprs: &[SpannedExpr]) -> Vec<ParseError> {
+    exprs.iter().filter_map(|e| match e.expr {
+        GCodeExpr::Unknown(block) => Some(ParseError::at(file, e, format!("unknown command `{block}`"))),
+        _ => None,
+    }).collect()
+}
+
+/// the XOR of all bytes, as used by the `*` checksum
+fn checksum(text: &str) -> u32 {
+    text.bytes().fold(0, |c, b| c ^ b) as u32
+}
+
+/// checks that line numbers increase, by one on lines with checksum, and that checksums match the text before them
+pub fn check_line_numbers(file: &str, exprs: &[SpannedExpr]) -> Vec<ParseError> {
+    let mut errors = Vec::new();
+    let mut last_number: Option<u32> = None;
+    for (k, e) in exprs.iter().enumerate() {
+        let line_start = file[..e.span.start].rfind('\n').map_or(0, |i| i + 1);
+        let error = |message| ParseError::at(file, e, message);
+        match e.expr {
+            GCodeExpr::LineNumber(n) => {
+                // Marlin needs consecutive numbers with a checksum, other files often count in steps like `N10`, `N20`
+                let framed = exprs[k + 1..].iter().take_while(|next| next.line == e.line).any(|next| matches!(next.expr, GCodeExpr::Checksum(_)));
+                match last_number {
+                    Some(last) if framed && last.checked_add(1) != Some(n) => errors.push(error(format!("`N{n}` does not follow `N{last}`"))),
+                    Some(last) if n <= last => errors.push(error(format!("`N{n}` is not larger than `N{last}`"))),
+                    _ => {},
+                }
+                last_number = Some(n);
+            },
+            GCodeExpr::Checksum(c) => {
+                let expected = checksum(&file[line_start..e.span.start]);
+                if c != expected {
+                    errors.push(error(format!("the checksum `*{c}` should be `*{expected}`")));
+                }
+            },
+            _ => {},
+        }
+    }
+    errors
+}
+
+/// how the `transform` changes the line numbers
+#[derive(Copy, Clone)]
+pub enum LineNumbering {
+    Add, // number all lines with commands
+    Renumber, // count the existing line numbers up from 1
+    Strip, // remove line numbers and checksums
+}
+
+impl std::str::FromStr for LineNumbering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(LineNumbering::Add),
+            "renumber" => Ok(LineNumbering::Renumber),
+            "strip" => Ok(LineNumbering::Strip),
+            _ => Err(format!("`{s}` is no line numbering, use `add`, `renumber` or `strip`")),
+        }
+    }
+}
+
+/// adds, renumbers or removes the `N` line numbers, lines with numbers also get a checksum
+pub fn number_lines(commands: Vec<(usize, GCodeExpr)>, numbering: LineNumbering) -> Vec<(usize, GCodeExpr)> {
+    let mut numbered = Vec::with_capacity(commands.len());
+    let mut n = 0;
+    let mut lines = commands.into_iter().peekable();
+    while let Some((l, _)) = lines.peek() {
+        let l = *l;
+        let mut line = Vec::new();
+        while let Some(cmd) = lines.next_if(|(next, _)| *next == l) {
+            line.push(cmd.1);
+        }
+        match numbering {
+            LineNumbering::Strip => line.retain(|cmd| !matches!(cmd, GCodeExpr::LineNumber(_) | GCodeExpr::Checksum(_))),
+            LineNumbering::Renumber => for cmd in line.iter_mut() {
+                if let GCodeExpr::LineNumber(_) = cmd {
+                    n += 1;
+                    *cmd = GCodeExpr::LineNumber(n);
+                }
+            },
+            LineNumbering::Add => {
+                line.retain(|cmd| !matches!(cmd, GCodeExpr::LineNumber(_) | GCodeExpr::Checksum(_)));
+                if line.iter().any(|cmd| matches!(cmd, GCodeExpr::Code(_) | GCodeExpr::Unknown(_) | GCodeExpr::Parametric(_) | GCodeExpr::Assign(..) | GCodeExpr::Control(..))) {
+                    n += 1;
+                    // the checksum follows the last command, comments may be in front of and after it
+                    let code_end = line.iter().rposition(|cmd| !matches!(cmd, GCodeExpr::Comment(..))).map_or(line.len(), |k| k + 1);
+                    line.insert(code_end, GCodeExpr::Checksum(0));
+                    line.insert(0, GCodeExpr::LineNumber(n));
+                }
+            },
+        }
+        numbered.extend(line.into_iter().map(|cmd| (l, cmd)));
+    }
+    numbered
+}
+
+/// saves commands to gcode, where the commands of a line are separated by spaces and empty lines are kept
+pub fn save(filename: &str, commands: Vec<(usize, GCodeExpr)>) {
+    std::fs::write(filename, to_gcode(&commands)).expect("Unable to save the gcode.");
+}
+
+/// the text of the commands, as written by `save`
+fn to_gcode(commands: &[(usize, GCodeExpr)]) -> String {
+    let mut res = String::new();
+    let mut line_start = 0;
+    for (i, (l, cmd)) in commands.iter().enumerate() {
+        if let GCodeExpr::InlineComment(..) = cmd {
+            continue; // written with its command
+        }
+        let new_lines = if i > 0 { l - commands[i - 1].0 } else { *l };
+        if new_lines > 0 {
+            res.push_str(&"\n".repeat(new_lines));
+            line_start = res.len();
+        } else if i > 0 && !matches!(cmd, GCodeExpr::Checksum(_)) {
+            res.push(' ');
+        }
+        match cmd {
+            GCodeExpr::Checksum(_) => res.push_str(&GCodeExpr::Checksum(checksum(&res[line_start..])).as_str()),
+            GCodeExpr::Code(code) => {
+                let comments: Vec<_> = commands[i + 1..].iter().map_while(|(_, c)| match c {
+                    GCodeExpr::InlineComment(_, letter) => Some((*letter, c.as_str())),
+                    _ => None,
+                }).collect();
+                res.push_str(&with_comments(code, comments));
+            },
+            _ => res.push_str(&cmd.as_str()),
+        }
+    }
+    if !res.is_empty() {
+        res.push('\n');
+    }
+    res
+}
+
+/// the text of a command, where each comment follows the word with its letter again (or the end, if the word is missing)
+fn with_comments(code: &CommentlessGCodeExpr, mut comments: Vec<(char, String)>) -> String {
+    let mut words = Vec::new();
+    for word in code.as_str().split(' ') {
+        words.push(word.to_string());
+        let letter = word.chars().next().unwrap_or_default().to_ascii_uppercase();
+        while let Some(k) = comments.iter().position(|(l, _)| *l == letter) {
+            words.push(comments.remove(k).1);
+        }
+    }
+    words.extend(comments.into_iter().map(|(_, comment)| comment));
+    words.join(" ")
 }
 
 /// saves new commands on tope
-pub fn resave(filename: Option<&str>, commands: &Vec<CommentlessGCodeExpr>) {
+pub fn resave(filename: Option<&str>, commands: &[CommentlessGCodeExpr]) {
     if let Some(filename) = filename {
         let oldfile = std::fs::read_to_string(filename).expect("unable to open the file.");
         std::fs::write(
@@ -243,4 +1067,118 @@ pub fn resave(filename: Option<&str>, commands: &Vec<CommentlessGCodeExpr>) {
             )
         ).expect("Unable to save the gcode.");
     }       
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the commands of a file, as passed to `save`
+    fn commands(file: &str) -> Vec<(usize, GCodeExpr<'_>)> {
+        let (exprs, errors) = parse_gcode(file);
+        assert!(errors.is_empty(), "cannot parse the file");
+        exprs.into_iter().map(|e| (e.line, e.expr)).collect()
+    }
+
+    #[test]
+    fn save_with_line_numbers() {
+        let file = "; header\nG21 G90\n\nG1 X10 (edge) Y5 F1500\n(start) G0 X1\n#1 = [2 * 3]\nG1 X#1 ; parametric\nM2\n";
+        let numbered = to_gcode(&number_lines(commands(file), LineNumbering::Add));
+        let (exprs, errors) = parse_gcode(&numbered);
+        assert!(errors.is_empty(), "cannot parse the saved file:\n{numbered}");
+        assert!(check_line_numbers(&numbered, &exprs).is_empty(), "wrong line numbers:\n{numbered}");
+        assert!(numbered.contains("X10 (edge)"));
+        let stripped = to_gcode(&number_lines(commands(&numbered), LineNumbering::Strip));
+        assert_eq!(stripped, file);
+    }
+
+    #[test]
+    fn line_number_steps() {
+        let file = "N10 G1 X1\nN20 G1 X2\nN30 G1 X3\n";
+        assert!(check_line_numbers(file, &parse_gcode(file).0).is_empty());
+        let file = "N10 G1 X1\nN10 G1 X2\nN5 G1 X3\n";
+        assert_eq!(check_line_numbers(file, &parse_gcode(file).0).len(), 2);
+        // with a checksum the numbers have to be consecutive
+        let file = "N1 G1 X1*96\nN3 G1 X2*97\n";
+        let errors = check_line_numbers(file, &parse_gcode(file).0);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn home_axes() {
+        let line = commands("G28 X0 Y0");
+        assert_eq!(line.len(), 1);
+        assert!(matches!(line[0].1, GCodeExpr::Code(CommentlessGCodeExpr::Home { X: Some(_), Y: Some(_), Z: None })));
+        assert_eq!(line[0].1.as_str(), "G28 X0 Y0");
+        for line in ["G28 X0 G1 Y5", "G92 X0 G0 X1", "G10 L2 P1 X5 G1 X1"] {
+            assert_eq!(parse_gcode(line).1.len(), 1, "`{line}` is accepted");
+        }
+        assert!(parse_gcode("G28 G1 X5").1.is_empty());
+    }
+
+    #[test]
+    fn coordinate_system_comments() {
+        for (file, system) in [("G54 (work)", 1), ("G55 ; second job", 2), ("g59(last)", 6)] {
+            let line = commands(file);
+            assert!(matches!(line[0].1, GCodeExpr::Code(CommentlessGCodeExpr::CoordinateSystem(p)) if p == system), "`{file}` is no `G{}`", 53 + system);
+            assert_eq!(to_gcode(&line), format!("G{} {}\n", 53 + system, &file[3..].trim()));
+        }
+    }
+
+    #[test]
+    fn program_end_comments() {
+        for (file, saved) in [("M30 ; end", "M30 ; end\n"), ("M30 (end)", "M30 (end)\n"), ("M02 (end)", "M2 (end)\n")] {
+            assert_eq!(to_gcode(&commands(file)), saved);
+        }
+    }
+
+    #[test]
+    fn other_offsets() {
+        // the firmware retract of Marlin and the other forms of LinuxCNC are kept as they are
+        for file in ["G10", "G10 L1 P1 X5", "G10 L20 P2 X0 Y0", "G10 L12 P1"] {
+            let line = commands(file);
+            assert_eq!(line.len(), 1, "`{file}` is split");
+            assert!(matches!(line[0].1, GCodeExpr::Unknown(block) if block == file));
+        }
+        let line = commands("G10 P2 L2 X1");
+        assert!(matches!(line[0].1, GCodeExpr::Code(CommentlessGCodeExpr::SetOffset { P: 2, X: Some(_), Y: None })));
+        assert_eq!(parse_gcode("G10 L2 X1").1.len(), 1);
+    }
+
+    #[test]
+    fn set_position_without_axes() {
+        // Marlin resets the extruder with `G92 E0`
+        for file in ["G92 E0", "G92"] {
+            let line = commands(file);
+            assert!(matches!(line[0].1, GCodeExpr::Code(CommentlessGCodeExpr::SetPosition { X: None, Y: None, Z: None })));
+            assert_eq!(to_gcode(&line), format!("{file}\n"));
+        }
+    }
+
+    #[test]
+    fn source_order() {
+        for file in ["G1 X5 G91", "G1 X1 (a) G90", "M2 G1 X1 M280 P0 S50"] {
+            assert_eq!(to_gcode(&commands(file)), format!("{file}\n"));
+        }
+        let line = parse_gcode("G1 X1 (a) G90").0;
+        assert!(line.windows(2).all(|pair| pair[0].span.end <= pair[1].span.start));
+    }
+
+    #[test]
+    fn unknown_words() {
+        let line = commands("G1 E1 X10 F1500");
+        assert!(line.iter().any(|(_, cmd)| matches!(cmd, GCodeExpr::Unknown("E1"))));
+        assert!(line.iter().any(|(_, cmd)| cmd.as_str() == "G1 X10 F1500"));
+        // the axis words of an unknown G-word stay with it
+        for file in ["G38.2 Z-10", "G30 X10", "M201 X500 Y500"] {
+            let line = commands(file);
+            assert_eq!(line.len(), 1, "`{file}` is split");
+            assert!(matches!(line[0].1, GCodeExpr::Unknown(block) if block == file));
+        }
+        // the rest of the block is raw text up to a comment or the checksum
+        for (file, block) in [("M117 Printing...", "M117 Printing..."), ("M23 file.gco ; print", "M23 file.gco"), ("M118 E1 done (msg)", "M118 E1 done"), ("N3 M117 Hi*38", "M117 Hi")] {
+            let line = commands(file);
+            assert!(line.iter().any(|(_, cmd)| matches!(cmd, GCodeExpr::Unknown(b) if *b == block)), "`{file}` has no `{block}`");
+            assert_eq!(to_gcode(&line).trim_end(), file);
+        }
+    }
+}